@@ -0,0 +1,259 @@
+use crate::definitions::*;
+use std::cell::RefCell;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone)]
+pub enum AttachedBuffer {
+    Shm {
+        buffer: WlBuffer,
+        format: ShmFormat,
+        size: Size<i32, Physical>,
+        stride: i32,
+    },
+    #[cfg(feature = "dma_buf")]
+    Dmabuf {
+        buffer: WlBuffer,
+        dmabuf: Dmabuf,
+    },
+    /// A 1x1 shm buffer, used by clients as a solid color fill.
+    SinglePixel {
+        buffer: WlBuffer,
+        rgba: [u8; 4],
+    },
+    Unknown {
+        buffer: WlBuffer,
+    },
+}
+impl AttachedBuffer {
+    pub fn resolve(buffer: &WlBuffer) -> Self {
+        #[cfg(feature = "dma_buf")]
+        if let Some(dmabuf) = buffer.as_ref().user_data().get::<Dmabuf>() {
+            return Self::Dmabuf {
+                buffer: buffer.clone(),
+                dmabuf: dmabuf.clone(),
+            };
+        }
+        let shm = with_buffer_contents(buffer, |slice, data| {
+            if data.width == 1 && data.height == 1 {
+                let offset = data.offset as usize;
+                if let Some(pixel) = slice.get(offset..offset + 4) {
+                    // Little endian, so the bytes are stored as b, g, r, a.
+                    match data.format {
+                        ShmFormat::Argb8888 => {
+                            return Self::SinglePixel {
+                                buffer: buffer.clone(),
+                                rgba: [pixel[2], pixel[1], pixel[0], pixel[3]],
+                            }
+                        }
+                        ShmFormat::Xrgb8888 => {
+                            return Self::SinglePixel {
+                                buffer: buffer.clone(),
+                                rgba: [pixel[2], pixel[1], pixel[0], 255],
+                            }
+                        }
+                        _ => (),
+                    }
+                }
+            }
+            Self::Shm {
+                buffer: buffer.clone(),
+                format: data.format,
+                size: (data.width, data.height).into(),
+                stride: data.stride,
+            }
+        });
+        match shm {
+            Ok(attached) => attached,
+            Err(_) => Self::Unknown {
+                buffer: buffer.clone(),
+            },
+        }
+    }
+
+    /// Interface of the global the buffer was created through.
+    pub(crate) fn global_interface(&self) -> Option<&'static str> {
+        match self {
+            Self::Shm { .. } | Self::SinglePixel { .. } => Some(WlShm::NAME),
+            #[cfg(feature = "dma_buf")]
+            Self::Dmabuf { .. } => Some(ZwpLinuxDmabufV1::NAME),
+            Self::Unknown { .. } => None,
+        }
+    }
+
+    pub fn buffer(&self) -> &WlBuffer {
+        match self {
+            Self::Shm { buffer, .. } => buffer,
+            #[cfg(feature = "dma_buf")]
+            Self::Dmabuf { buffer, .. } => buffer,
+            Self::SinglePixel { buffer, .. } => buffer,
+            Self::Unknown { buffer } => buffer,
+        }
+    }
+
+    pub fn size(&self) -> Option<Size<i32, Physical>> {
+        match self {
+            Self::Shm { size, .. } => Some(*size),
+            #[cfg(feature = "dma_buf")]
+            Self::Dmabuf { dmabuf, .. } => {
+                Some((dmabuf.width() as i32, dmabuf.height() as i32).into())
+            }
+            Self::SinglePixel { .. } => Some((1, 1).into()),
+            Self::Unknown { .. } => None,
+        }
+    }
+
+    /// Gives the pixel rows of a shm buffer, `stride` bytes each.
+    pub fn with_shm_data<T>(&self, f: impl FnOnce(&[u8]) -> T) -> Option<T> {
+        match self {
+            Self::Shm { buffer, .. } | Self::SinglePixel { buffer, .. } => {
+                with_buffer_contents(buffer, |slice, data| {
+                    let start = data.offset as usize;
+                    let end = start + (data.stride * data.height) as usize;
+                    slice.get(start..end).map(f)
+                })
+                .ok()
+                .flatten()
+            }
+            _ => None,
+        }
+    }
+}
+
+struct HeldBuffer {
+    buffer: AttachedBuffer,
+    attached_at: Instant,
+    released: bool,
+    warned: bool,
+}
+impl HeldBuffer {
+    fn release(&mut self) {
+        if !self.released {
+            self.buffer.buffer().release();
+            self.released = true;
+        }
+    }
+}
+
+#[derive(Default)]
+struct CurrentBuffer(RefCell<Option<HeldBuffer>>);
+
+pub(crate) fn current_buffer(surface_data: &SurfaceData) -> Option<AttachedBuffer> {
+    surface_data
+        .data_map
+        .get::<CurrentBuffer>()
+        .and_then(|current| current.0.borrow().as_ref().map(|held| held.buffer.clone()))
+}
+
+/// Sends `wl_buffer.release` for the current buffer of the surface, if not done yet.
+pub(crate) fn release_current(surface_data: &SurfaceData) {
+    if let Some(current) = surface_data.data_map.get::<CurrentBuffer>() {
+        if let Some(held) = current.0.borrow_mut().as_mut() {
+            held.release();
+        }
+    }
+}
+
+pub(crate) fn warn_held_too_long(surface: &WlSurface, threshold: Duration) {
+    let _ = with_states(surface, |surface_data| {
+        if let Some(current) = surface_data.data_map.get::<CurrentBuffer>() {
+            if let Some(held) = current.0.borrow_mut().as_mut() {
+                if !held.released && !held.warned && held.attached_at.elapsed() > threshold {
+                    held.warned = true;
+                    log::warn!(target: "EWS","Buffer {:?} of surface {:?} held for more than {:?} without release",held.buffer.buffer(),surface,threshold);
+                }
+            }
+        }
+    });
+}
+
+pub(crate) struct CommitState {
+    pub buffer: Option<AttachedBuffer>,
+    pub scale: i32,
+    pub transform: wl_output::Transform,
+    pub offset: Point<i32, Logical>,
+}
+
+/// Takes the buffer assignment out of the committed attributes and resolves it,
+/// the buffer stays the current one of the surface until replaced or removed,
+/// at which point it is released. The damage is moved to the accumulated damage.
+/// `None` if the commit did not change the buffer nor add damage.
+fn commit_state(surface_data: &SurfaceData) -> Option<CommitState> {
+    let mut attributes = surface_data.cached_state.current::<SurfaceAttributes>();
+    if attributes.buffer.is_none() && attributes.damage.is_empty() {
+        return None;
+    }
+    surface_data
+        .data_map
+        .insert_if_missing(CurrentBuffer::default);
+    let mut current = surface_data
+        .data_map
+        .get::<CurrentBuffer>()
+        .unwrap()
+        .0
+        .borrow_mut();
+    let mut offset = (0, 0).into();
+    match attributes.buffer.take() {
+        Some(BufferAssignment::NewBuffer { buffer, delta }) => {
+            if let Some(mut previous) = current.take() {
+                // Attaching the same buffer again hands it back to the compositor.
+                if previous.buffer.buffer() != &buffer {
+                    previous.release();
+                }
+            }
+            *current = Some(HeldBuffer {
+                buffer: AttachedBuffer::resolve(&buffer),
+                attached_at: Instant::now(),
+                released: false,
+                warned: false,
+            });
+            offset = delta;
+        }
+        Some(BufferAssignment::Removed) => {
+            if let Some(mut previous) = current.take() {
+                previous.release();
+            }
+        }
+        None => (),
+    }
+    crate::damage::accumulate(
+        surface_data,
+        &mut attributes,
+        current.as_ref().and_then(|held| held.buffer.size()),
+    );
+    Some(CommitState {
+        buffer: current.as_ref().map(|held| held.buffer.clone()),
+        scale: attributes.buffer_scale,
+        transform: attributes.buffer_transform,
+        offset,
+    })
+}
+
+/// Resolves the state applied by a commit, on the committed surface and on the
+/// synchronized subsurfaces whose cached state got applied along with it.
+/// The committed surface is always reported, its subsurfaces only when changed.
+pub(crate) fn commit_tree(surface: &WlSurface) -> Vec<(WlSurface, CommitState)> {
+    let mut states = Vec::new();
+    with_surface_tree_upward(
+        surface,
+        (),
+        |_, _, _| TraversalAction::DoChildren(()),
+        |node, surface_data, _| {
+            let state = commit_state(surface_data).or_else(|| {
+                (node == surface).then(|| {
+                    let attributes = surface_data.cached_state.current::<SurfaceAttributes>();
+                    CommitState {
+                        buffer: current_buffer(surface_data),
+                        scale: attributes.buffer_scale,
+                        transform: attributes.buffer_transform,
+                        offset: (0, 0).into(),
+                    }
+                })
+            });
+            if let Some(state) = state {
+                states.push((node.clone(), state));
+            }
+        },
+        |_, _, _| true,
+    );
+    states
+}
@@ -0,0 +1,109 @@
+use crate::buffer::current_buffer;
+use crate::definitions::*;
+use crate::pixel::{bytes_per_pixel, premultiplied_rgba, unpremultiply};
+use std::io::{self, Write};
+use std::path::Path;
+
+/// Straight alpha RGBA pixels, row by row.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RgbaImage {
+    pub width: u32,
+    pub height: u32,
+    pub data: Vec<u8>,
+}
+impl RgbaImage {
+    pub fn new(width: u32, height: u32) -> Self {
+        Self {
+            width,
+            height,
+            data: vec![0; (width * height * 4) as usize],
+        }
+    }
+    pub fn pixel(&self, x: u32, y: u32) -> [u8; 4] {
+        let offset = ((y * self.width + x) * 4) as usize;
+        let mut pixel = [0; 4];
+        pixel.copy_from_slice(&self.data[offset..offset + 4]);
+        pixel
+    }
+
+    /// Binary PPM, the alpha channel is dropped.
+    pub fn write_ppm(&self, mut writer: impl Write) -> io::Result<()> {
+        write!(writer, "P6\n{} {}\n255\n", self.width, self.height)?;
+        let rgb: Vec<u8> = self
+            .data
+            .chunks_exact(4)
+            .flat_map(|pixel| pixel[..3].iter().copied())
+            .collect();
+        writer.write_all(&rgb)
+    }
+    pub fn save_ppm(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let mut writer = io::BufWriter::new(std::fs::File::create(path)?);
+        self.write_ppm(&mut writer)?;
+        writer.flush()
+    }
+
+    #[cfg(feature = "png")]
+    pub fn write_png(&self, writer: impl Write) -> io::Result<()> {
+        let mut encoder = png::Encoder::new(writer, self.width, self.height);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder
+            .write_header()
+            .and_then(|mut writer| {
+                writer.write_image_data(&self.data)?;
+                writer.finish()
+            })
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err))
+    }
+    #[cfg(feature = "png")]
+    pub fn save_png(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let mut writer = io::BufWriter::new(std::fs::File::create(path)?);
+        self.write_png(&mut writer)?;
+        writer.flush()
+    }
+}
+
+/// Copies the current shm buffer of the surface as is, without applying its scale
+/// or transform. `None` if the surface has no shm buffer in a supported format.
+pub fn capture_surface(surface: &WlSurface) -> Option<RgbaImage> {
+    let buffer = with_states(surface, current_buffer).ok().flatten()?;
+    match &buffer {
+        AttachedBuffer::SinglePixel { rgba, .. } => {
+            let mut image = RgbaImage::new(1, 1);
+            image.data.copy_from_slice(&unpremultiply(*rgba));
+            Some(image)
+        }
+        AttachedBuffer::Shm {
+            format,
+            size,
+            stride,
+            ..
+        } => {
+            let bpp = bytes_per_pixel(*format)?;
+            let mut image = RgbaImage::new(size.w as u32, size.h as u32);
+            buffer.with_shm_data(|data| {
+                let row_length = size.w as usize * bpp;
+                let rows = data.chunks(*stride as usize).take(size.h as usize);
+                for (target, row) in image.data.chunks_exact_mut(size.w as usize * 4).zip(rows) {
+                    let pixels = row[..row_length.min(row.len())].chunks_exact(bpp);
+                    for (target, pixel) in target.chunks_exact_mut(4).zip(pixels) {
+                        target.copy_from_slice(&unpremultiply(premultiplied_rgba(*format, pixel)));
+                    }
+                }
+            })?;
+            Some(image)
+        }
+        _ => None,
+    }
+}
+
+/// Composites the surface with its subsurfaces, cropped to the area they cover.
+#[cfg(feature = "software_renderer")]
+pub fn capture_surface_tree(surface: &WlSurface) -> Option<RgbaImage> {
+    let bounds = crate::software_renderer::tree_bounds(surface)?;
+    let renderer = crate::software_renderer::SoftwareRenderer::default();
+    Some(renderer.render(
+        bounds.size,
+        std::iter::once((surface.clone(), Point::from((-bounds.loc.x, -bounds.loc.y)))),
+    ))
+}
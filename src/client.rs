@@ -0,0 +1,133 @@
+use crate::definitions::*;
+use slab::Slab;
+use std::os::unix::io::RawFd;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClientOrigin {
+    Internal,
+    External,
+}
+
+#[derive(Default)]
+pub(crate) struct ClientRegistry {
+    clients: Slab<Client>,
+    removed: Vec<ClientId>,
+}
+impl ClientRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register(
+        &mut self,
+        client: &Client,
+        origin: ClientOrigin,
+        credentials: Option<ClientCredentials>,
+    ) -> ClientId {
+        if let Some(id) = client_id(client) {
+            return id;
+        }
+        let id = ClientId(self.clients.insert(client.clone()));
+        client.data_map().insert_if_missing(|| id);
+        client.data_map().insert_if_missing(|| origin);
+        if let Some(credentials) = credentials {
+            client.data_map().insert_if_missing(|| credentials);
+        }
+        id
+    }
+
+    /// Like surface ids, the id stays reserved until `release_removed` is called,
+    /// once the `ClientDisconnected` request has been delivered.
+    pub fn mark_removed(&mut self, id: ClientId) {
+        if self.clients.contains(id.0) && !self.removed.contains(&id) {
+            self.removed.push(id);
+        }
+    }
+
+    pub fn release_removed(&mut self) {
+        for id in self.removed.drain(..) {
+            if self.clients.contains(id.0) {
+                self.clients.remove(id.0);
+            }
+        }
+    }
+
+    pub fn get(&self, id: ClientId) -> Option<&Client> {
+        if self.removed.contains(&id) {
+            return None;
+        }
+        self.clients.get(id.0)
+    }
+
+    pub fn ids(&self) -> impl Iterator<Item = ClientId> + '_ {
+        self.clients
+            .iter()
+            .map(|(id, _client)| ClientId(id))
+            .filter(move |id| !self.removed.contains(id))
+    }
+
+    pub fn origin(&self, id: ClientId) -> Option<ClientOrigin> {
+        self.get(id)
+            .and_then(|client| client.data_map().get::<ClientOrigin>().copied())
+    }
+}
+impl std::fmt::Debug for ClientRegistry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list().entries(self.ids()).finish()
+    }
+}
+
+pub fn client_id(client: &Client) -> Option<ClientId> {
+    client.data_map().get::<ClientId>().copied()
+}
+
+pub fn client_credentials(client: &Client) -> Option<&ClientCredentials> {
+    client.data_map().get::<ClientCredentials>()
+}
+
+impl ClientCredentials {
+    pub fn new(pid: i32, uid: u32, gid: u32) -> Self {
+        let proc_dir = PathBuf::from(format!("/proc/{}", pid));
+        let executable = std::fs::read_link(proc_dir.join("exe")).ok();
+        let cmdline = std::fs::read(proc_dir.join("cmdline"))
+            .map(|cmdline| {
+                cmdline
+                    .split(|byte| *byte == 0)
+                    .filter(|arg| !arg.is_empty())
+                    .map(|arg| String::from_utf8_lossy(arg).into_owned())
+                    .collect()
+            })
+            .unwrap_or_default();
+        Self {
+            pid,
+            uid,
+            gid,
+            executable,
+            cmdline,
+        }
+    }
+}
+
+pub(crate) fn peer_credentials(fd: RawFd) -> Option<ClientCredentials> {
+    let mut ucred = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut ucred as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if result == 0 {
+        Some(ClientCredentials::new(ucred.pid, ucred.uid, ucred.gid))
+    } else {
+        log::error!(target: "EWS","peer_credentials: SO_PEERCRED failed: {}",std::io::Error::last_os_error());
+        None
+    }
+}
@@ -0,0 +1,159 @@
+use crate::definitions::*;
+use std::cell::RefCell;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigureReason {
+    InitialMap,
+    StateChange,
+    OutputChange,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ConfigureDecision {
+    /// Toplevels only, `None` lets the client choose.
+    pub size: Option<Size<i32, Logical>>,
+    /// Toplevels only, replaces the whole state set.
+    pub states: Vec<SurfaceState>,
+    /// Popups only, relative to the parent, `None` keeps the positioner geometry.
+    pub geometry: Option<Rectangle<i32, Logical>>,
+}
+
+pub trait ConfigurePolicy {
+    fn configure(&mut self, surface: &SurfaceKind, reason: ConfigureReason) -> ConfigureDecision;
+}
+impl<F> ConfigurePolicy for F
+where
+    F: FnMut(&SurfaceKind, ConfigureReason) -> ConfigureDecision,
+{
+    fn configure(&mut self, surface: &SurfaceKind, reason: ConfigureReason) -> ConfigureDecision {
+        self(surface, reason)
+    }
+}
+impl std::fmt::Debug for dyn ConfigurePolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ConfigurePolicy")
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct ConfigureScheduler {
+    pending: Vec<(SurfaceKind, ConfigureReason)>,
+}
+impl ConfigureScheduler {
+    /// Several changes of the same surface before the next flush end up in one configure.
+    pub fn schedule(&mut self, surface: SurfaceKind, reason: ConfigureReason) {
+        let wl_surface = kind_surface(&surface);
+        if wl_surface.is_none()
+            || self
+                .pending
+                .iter()
+                .any(|(pending, _reason)| kind_surface(pending) == wl_surface)
+        {
+            return;
+        }
+        self.pending.push((surface, reason));
+    }
+
+    pub fn take(&mut self) -> Vec<(SurfaceKind, ConfigureReason)> {
+        std::mem::take(&mut self.pending)
+    }
+}
+
+pub(crate) fn kind_surface(surface: &SurfaceKind) -> Option<WlSurface> {
+    match surface {
+        SurfaceKind::Toplevel(toplevel) => toplevel.get_surface().cloned(),
+        SurfaceKind::Popup(popup) => popup.get_surface().cloned(),
+        SurfaceKind::Cursor => None,
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct ConfigureState {
+    /// Serial taken right before the last configure was sent, Smithay does not return
+    /// the serial of the configure itself, which is the next one.
+    awaiting: Option<Serial>,
+    last_acked: Option<Serial>,
+}
+
+#[derive(Default)]
+struct ConfigureTracker(RefCell<ConfigureState>);
+
+fn with_tracker<T>(surface: &WlSurface, f: impl FnOnce(&mut ConfigureState) -> T) -> Option<T> {
+    with_states(surface, |surface_data| {
+        surface_data
+            .data_map
+            .insert_if_missing(ConfigureTracker::default);
+        let tracker = surface_data.data_map.get::<ConfigureTracker>().unwrap();
+        let mut state = tracker.0.borrow_mut();
+        f(&mut state)
+    })
+    .ok()
+}
+
+/// Whether `serial` comes after `marker`, serials wrap around.
+pub(crate) fn is_after(serial: Serial, marker: Serial) -> bool {
+    (u32::from(serial).wrapping_sub(u32::from(marker)) as i32) > 0
+}
+
+/// Sends a configure with `send` and waits for its acknowledgement, returns the serial
+/// taken right before sending, which any ack of this configure comes after.
+pub(crate) fn send_tracked(surface: &WlSurface, send: impl FnOnce()) -> Serial {
+    let marker = SERIAL_COUNTER.next_serial();
+    send();
+    with_tracker(surface, |state| state.awaiting = Some(marker));
+    marker
+}
+
+pub(crate) fn apply(surface: &SurfaceKind, decision: ConfigureDecision) -> Option<Serial> {
+    let wl_surface = kind_surface(surface)?;
+    let result = match surface {
+        SurfaceKind::Toplevel(toplevel) => toplevel
+            .with_pending_state(|state| {
+                state.size = decision.size;
+                state.states = ToplevelStateSet::default();
+                for surface_state in decision.states {
+                    state.states.set(surface_state);
+                }
+            })
+            .map(|()| send_tracked(&wl_surface, || toplevel.send_configure())),
+        SurfaceKind::Popup(popup) => popup
+            .with_pending_state(|state| {
+                if let Some(geometry) = decision.geometry {
+                    state.geometry = geometry;
+                }
+            })
+            .map(|()| send_tracked(&wl_surface, || popup.send_configure())),
+        SurfaceKind::Cursor => return None,
+    };
+    match result {
+        Ok(marker) => Some(marker),
+        Err(err) => {
+            log::error!(target: "EWS","Error while sending configure: {:#?}",err);
+            None
+        }
+    }
+}
+
+/// An acknowledgement of an older configure leaves the last one awaited.
+pub(crate) fn acked(surface: &WlSurface, configure: &Configure) {
+    let serial = match configure {
+        Configure::Toplevel(configure) => configure.serial,
+        Configure::Popup(configure) => configure.serial,
+    };
+    with_tracker(surface, |state| {
+        if state
+            .awaiting
+            .map_or(false, |marker| is_after(serial, marker))
+        {
+            state.awaiting = None;
+        }
+        state.last_acked = Some(serial);
+    });
+}
+
+pub(crate) fn last_acked_serial(surface: &WlSurface) -> Option<Serial> {
+    with_tracker(surface, |state| state.last_acked).flatten()
+}
+pub(crate) fn awaiting_ack(surface: &WlSurface) -> bool {
+    with_tracker(surface, |state| state.awaiting.is_some()).unwrap_or(false)
+}
@@ -0,0 +1,171 @@
+use crate::definitions::*;
+use crate::surface::subsurface_offset;
+use std::cell::RefCell;
+
+/// Past this many rectangles the accumulated damage collapses into its bounding box,
+/// so that a surface nobody repaints cannot grow it without limit.
+const MAX_DAMAGE_RECTS: usize = 32;
+
+#[derive(Default)]
+struct AccumulatedDamage(RefCell<Vec<Rectangle<i32, Logical>>>);
+
+pub(crate) fn add_damage(damage: &mut Vec<Rectangle<i32, Logical>>, rect: Rectangle<i32, Logical>) {
+    damage.push(rect);
+    if damage.len() > MAX_DAMAGE_RECTS {
+        let bounds = damage
+            .drain(..)
+            .map(|rect| {
+                (
+                    rect.loc.x,
+                    rect.loc.y,
+                    rect.loc.x + rect.size.w,
+                    rect.loc.y + rect.size.h,
+                )
+            })
+            .fold(None, |bounds, (left, top, right, bottom)| {
+                Some(match bounds {
+                    Some((l, t, r, b)) => (l.min(left), t.min(top), r.max(right), b.max(bottom)),
+                    None => (left, top, right, bottom),
+                })
+            });
+        if let Some((left, top, right, bottom)) = bounds {
+            damage.push(Rectangle::from_loc_and_size(
+                (left, top),
+                (right - left, bottom - top),
+            ));
+        }
+    }
+}
+
+/// Maps a rectangle in buffer pixels to surface-local logical coordinates,
+/// undoing the buffer transform and scale. The transforms follow Weston and wlroots.
+pub(crate) fn buffer_to_surface(
+    (x, y, width, height): (i32, i32, i32, i32),
+    buffer_size: Size<i32, Physical>,
+    scale: i32,
+    transform: wl_output::Transform,
+) -> Rectangle<i32, Logical> {
+    use wl_output::Transform;
+    let (buffer_width, buffer_height) = (buffer_size.w, buffer_size.h);
+    let map = |bx: i32, by: i32| match transform {
+        Transform::_90 => (buffer_height - by, bx),
+        Transform::_180 => (buffer_width - bx, buffer_height - by),
+        Transform::_270 => (by, buffer_width - bx),
+        Transform::Flipped => (buffer_width - bx, by),
+        Transform::Flipped90 => (by, bx),
+        Transform::Flipped180 => (bx, buffer_height - by),
+        Transform::Flipped270 => (buffer_height - by, buffer_width - bx),
+        _ => (bx, by),
+    };
+    let (x1, y1) = map(x, y);
+    let (x2, y2) = map(x + width, y + height);
+    let scale = scale.max(1);
+    // Rounded outwards so that partially covered logical pixels stay damaged.
+    let left = x1.min(x2).div_euclid(scale);
+    let top = y1.min(y2).div_euclid(scale);
+    let right = (x1.max(x2) + scale - 1).div_euclid(scale);
+    let bottom = (y1.max(y2) + scale - 1).div_euclid(scale);
+    Rectangle::from_loc_and_size((left, top), (right - left, bottom - top))
+}
+
+/// Moves the damage of the committed attributes into the accumulated damage of the surface.
+pub(crate) fn accumulate(
+    surface_data: &SurfaceData,
+    attributes: &mut SurfaceAttributes,
+    buffer_size: Option<Size<i32, Physical>>,
+) {
+    surface_data
+        .data_map
+        .insert_if_missing(AccumulatedDamage::default);
+    let mut accumulated = surface_data
+        .data_map
+        .get::<AccumulatedDamage>()
+        .unwrap()
+        .0
+        .borrow_mut();
+    for damage in attributes.damage.drain(..) {
+        match damage {
+            Damage::Surface(rect) => add_damage(&mut accumulated, rect),
+            // Without a buffer there is nothing the buffer damage could refer to.
+            Damage::Buffer(rect) => {
+                if let Some(buffer_size) = buffer_size {
+                    let rect = buffer_to_surface(
+                        (rect.loc.x, rect.loc.y, rect.size.w, rect.size.h),
+                        buffer_size,
+                        attributes.buffer_scale,
+                        attributes.buffer_transform,
+                    );
+                    add_damage(&mut accumulated, rect);
+                }
+            }
+        }
+    }
+}
+
+pub(crate) fn surface_damage(surface_data: &SurfaceData) -> Vec<Rectangle<i32, Logical>> {
+    surface_data
+        .data_map
+        .get::<AccumulatedDamage>()
+        .map(|accumulated| accumulated.0.borrow().clone())
+        .unwrap_or_default()
+}
+
+/// Damage of the surface and its subsurfaces, relative to `location`.
+pub(crate) fn tree_damage(
+    surface: &WlSurface,
+    location: Point<i32, Logical>,
+) -> Vec<Rectangle<i32, Logical>> {
+    collect_tree_damage(surface, location, false)
+}
+
+/// Drains the damage of the surface and its subsurfaces, relative to the surface.
+pub(crate) fn take_tree_damage(surface: &WlSurface) -> Vec<Rectangle<i32, Logical>> {
+    collect_tree_damage(surface, (0, 0).into(), true)
+}
+
+fn collect_tree_damage(
+    surface: &WlSurface,
+    location: Point<i32, Logical>,
+    take: bool,
+) -> Vec<Rectangle<i32, Logical>> {
+    let mut damage = Vec::new();
+    with_surface_tree_downward(
+        surface,
+        location,
+        |_, surface_data, &location| {
+            TraversalAction::DoChildren(location + subsurface_offset(surface_data))
+        },
+        |_, surface_data, &location| {
+            let location = location + subsurface_offset(surface_data);
+            let surface_damage = match surface_data.data_map.get::<AccumulatedDamage>() {
+                Some(accumulated) if take => std::mem::take(&mut *accumulated.0.borrow_mut()),
+                Some(accumulated) => accumulated.0.borrow().clone(),
+                None => return,
+            };
+            damage.extend(surface_damage.into_iter().map(|mut rect| {
+                rect.loc = rect.loc + location;
+                rect
+            }));
+        },
+        |_, _, _| true,
+    );
+    damage
+}
+
+pub(crate) fn clip(
+    rect: Rectangle<i32, Logical>,
+    area: Rectangle<i32, Logical>,
+) -> Option<Rectangle<i32, Logical>> {
+    let left = rect.loc.x.max(area.loc.x);
+    let top = rect.loc.y.max(area.loc.y);
+    let right = (rect.loc.x + rect.size.w).min(area.loc.x + area.size.w);
+    let bottom = (rect.loc.y + rect.size.h).min(area.loc.y + area.size.h);
+    if left < right && top < bottom {
+        Some(Rectangle::from_loc_and_size(
+            (left, top),
+            (right - left, bottom - top),
+        ))
+    } else {
+        None
+    }
+}
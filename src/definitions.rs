@@ -8,8 +8,13 @@ pub use smithay::{
         renderer::{buffer_type, BufferType},
     },
     reexports::{
+        calloop,
         wayland_commons::user_data::UserDataMap,
         wayland_protocols::{
+            presentation_time::server::{
+                wp_presentation::{self, WpPresentation},
+                wp_presentation_feedback::{self, WpPresentationFeedback},
+            },
             unstable::{
                 linux_dmabuf::v1::server::zwp_linux_dmabuf_v1::ZwpLinuxDmabufV1,
                 linux_explicit_synchronization::v1::server::zwp_linux_explicit_synchronization_v1::ZwpLinuxExplicitSynchronizationV1,
@@ -25,7 +30,7 @@ pub use smithay::{
             protocol::{
                 wl_buffer::WlBuffer,
                 wl_compositor::WlCompositor,
-                wl_data_device_manager::WlDataDeviceManager,
+                wl_data_device_manager::{DndAction, WlDataDeviceManager},
                 wl_keyboard::WlKeyboard,
                 wl_output::{Subpixel, WlOutput},
                 wl_pointer::{Axis, AxisSource, ButtonState, WlPointer},
@@ -42,20 +47,20 @@ pub use smithay::{
             Client, Display, Filter, Global, Interface, Main, Resource,
         },
     },
-    utils::{DeadResource, Logical, Point, Rectangle, Size},
+    utils::{DeadResource, Logical, Physical, Point, Rectangle, Size},
     wayland::{
         compositor::*,
         data_device::DataDeviceEvent,
         data_device::{default_action_chooser, init_data_device},
         explicit_synchronization::init_explicit_synchronization_global,
-        output::{Output, PhysicalProperties},
+        output::{Mode as OutputMode, Output, PhysicalProperties},
         seat::{
-            AxisFrame, CursorImageStatus, FilterResult, GrabStartData, KeyboardHandle, PointerGrab,
-            PointerHandle, PointerInnerHandle, Seat, XkbConfig,
+            AxisFrame, CursorImageStatus, FilterResult, GrabStartData, KeyboardError,
+            KeyboardHandle, PointerGrab, PointerHandle, PointerInnerHandle, Seat, XkbConfig,
         },
         shell::xdg::{
-            Configure, PopupSurface, ShellState as XdgShellState, SurfaceCachedState,
-            ToplevelState, ToplevelStateSet, ToplevelSurface, XdgRequest,
+            Configure, PopupSurface, PositionerState, ShellState as XdgShellState,
+            SurfaceCachedState, ToplevelState, ToplevelStateSet, ToplevelSurface, XdgRequest,
         },
         shm::{with_buffer_contents, BufferData},
         Serial, SERIAL_COUNTER,
@@ -64,6 +69,8 @@ pub use smithay::{
 
 pub use wayland_cursor::{Cursor, CursorTheme};
 
+pub use std::os::unix::net::UnixListener;
+pub use std::path::PathBuf;
 pub use std::sync::{Arc, Mutex};
 
 #[derive(Debug)]
@@ -74,20 +81,66 @@ pub enum SeatRequest {
 
 #[derive(Debug)]
 pub enum WaylandRequest {
+    /// `credentials` is `None` for clients created in-process by `create_client`.
+    ClientConnected {
+        id: ClientId,
+        credentials: Option<ClientCredentials>,
+    },
+    ClientDisconnected {
+        id: ClientId,
+    },
     Seat {
         seat: Seat,
         request: SeatRequest,
     },
+    /// Smithay has no hook on `wl_compositor.create_surface`, so a surface is reported
+    /// when first seen: on its first commit or when it gets an xdg or cursor role.
+    /// A surface destroyed before that never gets an id.
+    SurfaceCreated {
+        id: SurfaceId,
+        surface: WlSurface,
+    },
+    SurfaceRoleChanged {
+        id: SurfaceId,
+        role: &'static str,
+    },
     SurfaceRemoved {
-        id: usize,
+        id: SurfaceId,
     },
+    /// Sent for the committed surface and for the synchronized subsurfaces whose
+    /// cached state got applied with it.
     Commit {
         surface: WlSurface,
+        buffer: Option<crate::buffer::AttachedBuffer>,
+        scale: i32,
+        transform: wl_output::Transform,
+        offset: Point<i32, Logical>,
+    },
+    #[cfg(feature = "subcompositor")]
+    SurfaceTreeChanged {
+        root: WlSurface,
     },
     #[cfg(feature = "xdg_shell")]
     XdgRequest {
         request: XdgRequest,
     },
+    #[cfg(feature = "xdg_shell")]
+    WindowStateApplied {
+        id: SurfaceId,
+        state: crate::window::WindowState,
+    },
+    /// New location of a window moved by the built-in move grab.
+    #[cfg(feature = "xdg_shell")]
+    WindowMoved {
+        id: SurfaceId,
+        location: Point<i32, Logical>,
+    },
+    /// New geometry of a window resized by the built-in resize grab.
+    #[cfg(feature = "xdg_shell")]
+    WindowResized {
+        id: SurfaceId,
+        geometry: Rectangle<i32, Logical>,
+    },
     #[cfg(feature = "dma_buf")]
     Dmabuf {
         buffer: Dmabuf,
@@ -98,15 +151,34 @@ pub enum WaylandRequest {
     },
 }
 
+#[derive(Debug)]
+pub enum SocketMode {
+    Auto,
+    Named(String),
+    Listener(UnixListener),
+    None,
+}
+impl Default for SocketMode {
+    fn default() -> Self {
+        Self::Auto
+    }
+}
+
 #[derive(Default)]
 pub struct Parameters {
+    pub socket: SocketMode,
+    /// See `GlobalFilter` for the globals it can hide and the ones it only enforces.
+    pub global_filter: Option<Box<dyn crate::filter::GlobalFilter>>,
     #[cfg(feature = "shm")]
     pub shm_formats: Vec<ShmFormat>,
     #[cfg(feature = "dma_buf")]
     pub drm_formats: Vec<DrmFormat>,
+    /// Clock advertised to presentation-time clients, `CLOCK_MONOTONIC` if unset.
+    #[cfg(feature = "presentation_time")]
+    pub presentation_clock_id: Option<u32>,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct ClientId(pub usize);
 impl From<ClientId> for usize {
     fn from(id: ClientId) -> Self {
@@ -129,6 +201,15 @@ impl From<usize> for ClientId {
     }
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientCredentials {
+    pub pid: i32,
+    pub uid: u32,
+    pub gid: u32,
+    pub executable: Option<PathBuf>,
+    pub cmdline: Vec<String>,
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct SeatId(pub usize);
 impl From<SeatId> for usize {
@@ -152,7 +233,7 @@ impl From<usize> for SeatId {
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct SurfaceId(pub usize);
 impl From<SurfaceId> for usize {
     fn from(id: SurfaceId) -> Self {
@@ -0,0 +1,83 @@
+use crate::definitions::*;
+
+pub trait DmabufValidator {
+    fn validate(&mut self, buffer: &Dmabuf) -> bool;
+}
+impl<F> DmabufValidator for F
+where
+    F: FnMut(&Dmabuf) -> bool,
+{
+    fn validate(&mut self, buffer: &Dmabuf) -> bool {
+        self(buffer)
+    }
+}
+impl std::fmt::Debug for dyn DmabufValidator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("DmabufValidator")
+    }
+}
+
+/// Validates imports without touching the GPU, checking format, modifier,
+/// plane count and size, so it can be used in headless tests.
+#[derive(Debug, Clone)]
+pub struct SoftwareDmabufValidator {
+    pub formats: Vec<DrmFormat>,
+    pub max_width: u32,
+    pub max_height: u32,
+    pub max_planes: usize,
+}
+impl SoftwareDmabufValidator {
+    pub fn new(formats: Vec<DrmFormat>) -> Self {
+        Self {
+            formats,
+            max_width: 16384,
+            max_height: 16384,
+            max_planes: 4,
+        }
+    }
+
+    /// Plane count of a linear buffer, `None` for formats missing from the table,
+    /// whose plane count is then only bounded by `max_planes`.
+    fn linear_planes(code: DrmFourcc) -> Option<usize> {
+        use crate::definitions::DrmFourcc::*;
+        match code {
+            Argb8888 | Xrgb8888 | Abgr8888 | Xbgr8888 | Rgba8888 | Rgbx8888 | Bgra8888
+            | Bgrx8888 | Rgb888 | Bgr888 | Rgb565 | Bgr565 | Argb2101010 | Xrgb2101010
+            | Abgr2101010 | Xbgr2101010 | Abgr16161616f | Xbgr16161616f | R8 | R16 | Gr88
+            | Yuyv | Yvyu | Uyvy | Vyuy | Ayuv => Some(1),
+            Nv12 | Nv21 | Nv16 | Nv61 | Nv24 | Nv42 | P010 | P012 | P016 => Some(2),
+            Yuv410 | Yvu410 | Yuv411 | Yvu411 | Yuv420 | Yvu420 | Yuv422 | Yvu422 | Yuv444
+            | Yvu444 => Some(3),
+            _ => None,
+        }
+    }
+}
+impl DmabufValidator for SoftwareDmabufValidator {
+    fn validate(&mut self, buffer: &Dmabuf) -> bool {
+        let format = buffer.format();
+        if !self.formats.contains(&format) {
+            log::debug!(target: "EWS","Rejected dmabuf import: unsupported format {:?}",format);
+            return false;
+        }
+
+        let planes = buffer.num_planes();
+        // Non linear modifiers may carry auxiliary planes, so only the upper bound is checked.
+        let expected_planes = match Self::linear_planes(format.code) {
+            Some(linear_planes) if format.modifier == DrmModifier::Linear => {
+                planes == linear_planes
+            }
+            _ => planes > 0,
+        };
+        if !expected_planes || planes > self.max_planes {
+            log::debug!(target: "EWS","Rejected dmabuf import: unexpected plane count {} for {:?}",planes,format);
+            return false;
+        }
+
+        let (width, height) = (buffer.width(), buffer.height());
+        if width == 0 || height == 0 || width > self.max_width || height > self.max_height {
+            log::debug!(target: "EWS","Rejected dmabuf import: invalid size {}x{}",width,height);
+            return false;
+        }
+        true
+    }
+}
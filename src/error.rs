@@ -0,0 +1,58 @@
+use crate::definitions::{ClientId, DeadResource, KeyboardError, SurfaceId};
+
+#[derive(Debug)]
+pub enum ServerError {
+    SocketBind(std::io::Error),
+    ClientSocket(std::io::Error),
+    Spawn(std::io::Error),
+    Keymap(KeyboardError),
+    UnknownSeat(usize),
+    UnknownOutput(usize),
+    UnknownClient(ClientId),
+    UnknownSurface(SurfaceId),
+    Dispatch(std::io::Error),
+    DeadResource,
+    Unimplemented(&'static str),
+}
+
+impl std::fmt::Display for ServerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::SocketBind(err) => write!(f, "Failed to bind wayland socket: {}", err),
+            Self::ClientSocket(err) => write!(f, "Failed to create client socket: {}", err),
+            Self::Spawn(err) => write!(f, "Failed to spawn client: {}", err),
+            Self::Keymap(err) => write!(f, "Failed to compile keymap: {:?}", err),
+            Self::UnknownSeat(id) => write!(f, "Unknown seat id {}", id),
+            Self::UnknownOutput(id) => write!(f, "Unknown output id {}", id),
+            Self::UnknownClient(id) => write!(f, "Unknown client id {}", id.0),
+            Self::UnknownSurface(id) => write!(f, "Unknown surface id {}", id.0),
+            Self::Dispatch(err) => write!(f, "Failed to dispatch clients: {}", err),
+            Self::DeadResource => write!(f, "The resource is no longer alive"),
+            Self::Unimplemented(what) => write!(f, "{} is not implemented", what),
+        }
+    }
+}
+
+impl std::error::Error for ServerError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::SocketBind(err)
+            | Self::ClientSocket(err)
+            | Self::Spawn(err)
+            | Self::Dispatch(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<DeadResource> for ServerError {
+    fn from(_: DeadResource) -> Self {
+        Self::DeadResource
+    }
+}
+
+impl From<KeyboardError> for ServerError {
+    fn from(err: KeyboardError) -> Self {
+        Self::Keymap(err)
+    }
+}
@@ -0,0 +1,64 @@
+use crate::client::{client_credentials, client_id};
+use crate::definitions::*;
+use std::rc::Rc;
+
+/// Decides which globals each client may use, from its credentials and the interface name.
+///
+/// Only the globals created by this crate (`wp_presentation`) are hidden from the registry
+/// of refused clients. Smithay creates the other ones without a filter hook, so for them
+/// the policy is enforced on use instead: a client refused `wl_compositor`, `wl_subcompositor`,
+/// `wl_shm`, `zwp_linux_dmabuf_v1`, `xdg_wm_base` or `wl_data_device_manager` is disconnected
+/// when it uses it. `wl_seat`, `wl_output` and `zwp_linux_explicit_synchronization_v1`
+/// cannot be restricted at all.
+pub trait GlobalFilter {
+    fn filter(&self, credentials: Option<&ClientCredentials>, interface: &str) -> bool;
+}
+impl<F> GlobalFilter for F
+where
+    F: Fn(Option<&ClientCredentials>, &str) -> bool,
+{
+    fn filter(&self, credentials: Option<&ClientCredentials>, interface: &str) -> bool {
+        self(credentials, interface)
+    }
+}
+
+#[derive(Clone, Default)]
+pub(crate) struct GlobalPolicy(Option<Rc<dyn GlobalFilter>>);
+impl GlobalPolicy {
+    pub fn new(filter: Option<Box<dyn GlobalFilter>>) -> Self {
+        Self(filter.map(Rc::from))
+    }
+
+    pub fn allows(&self, client: &Client, interface: &str) -> bool {
+        match &self.0 {
+            Some(filter) => filter.filter(client_credentials(client), interface),
+            None => true,
+        }
+    }
+
+    #[cfg(feature = "presentation_time")]
+    pub fn client_filter(&self, interface: &'static str) -> impl FnMut(Client) -> bool + 'static {
+        let policy = self.clone();
+        move |client| policy.allows(&client, interface)
+    }
+
+    /// Globals created through Smithay cannot be filtered when advertised,
+    /// so a client using one of them against the policy gets disconnected.
+    pub fn enforce(&self, client: Option<Client>, interface: &str) -> bool {
+        match client {
+            Some(client) if !self.allows(&client, interface) => {
+                log::warn!(target: "EWS","Client {:?} is not allowed to use {}, disconnecting it",client_id(&client),interface);
+                client.kill();
+                false
+            }
+            _ => true,
+        }
+    }
+}
+impl std::fmt::Debug for GlobalPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("GlobalPolicy")
+            .field(&self.0.is_some())
+            .finish()
+    }
+}
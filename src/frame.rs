@@ -0,0 +1,82 @@
+use crate::definitions::*;
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::time::{Duration, Instant};
+
+#[derive(Default)]
+struct FrameState {
+    outputs: HashSet<usize>,
+    last_hidden_frame: Option<Instant>,
+}
+
+#[derive(Default)]
+struct SurfaceFrameState(RefCell<FrameState>);
+
+fn with_frame_state<T>(surface: &WlSurface, f: impl FnOnce(&mut FrameState) -> T) -> Option<T> {
+    with_states(surface, |surface_data| {
+        surface_data
+            .data_map
+            .insert_if_missing(SurfaceFrameState::default);
+        let frame_state = surface_data.data_map.get::<SurfaceFrameState>().unwrap();
+        let mut frame_state = frame_state.0.borrow_mut();
+        f(&mut frame_state)
+    })
+    .ok()
+}
+
+/// Returns `false` if the surface was already on the output.
+pub(crate) fn enter_output(surface: &WlSurface, output_id: usize) -> bool {
+    with_frame_state(surface, |frame_state| frame_state.outputs.insert(output_id)).unwrap_or(false)
+}
+/// Returns `false` if the surface was not on the output.
+pub(crate) fn leave_output(surface: &WlSurface, output_id: usize) -> bool {
+    with_frame_state(surface, |frame_state| {
+        frame_state.outputs.remove(&output_id)
+    })
+    .unwrap_or(false)
+}
+pub(crate) fn outputs(surface: &WlSurface) -> Vec<usize> {
+    with_frame_state(surface, |frame_state| {
+        frame_state.outputs.iter().copied().collect()
+    })
+    .unwrap_or_default()
+}
+
+/// Whether the tree of `surface` should get its frame callbacks for a frame of `output_id`.
+/// Surfaces on no output at all get them at most once per `hidden_interval`.
+pub(crate) fn frame_due(surface: &WlSurface, output_id: usize, hidden_interval: Duration) -> bool {
+    with_frame_state(surface, |frame_state| {
+        if !frame_state.outputs.is_empty() {
+            return frame_state.outputs.contains(&output_id);
+        }
+        let now = Instant::now();
+        match frame_state.last_hidden_frame {
+            Some(last_frame) if now.duration_since(last_frame) < hidden_interval => false,
+            _ => {
+                frame_state.last_hidden_frame = Some(now);
+                true
+            }
+        }
+    })
+    .unwrap_or(false)
+}
+
+/// Drains the frame callbacks of the surface and of its subsurfaces.
+pub(crate) fn send_frame_callbacks(surface: &WlSurface, time: u32) {
+    with_surface_tree_downward(
+        surface,
+        (),
+        |_, _, &()| TraversalAction::DoChildren(()),
+        |_, surface_data, &()| {
+            for callback in surface_data
+                .cached_state
+                .current::<SurfaceAttributes>()
+                .frame_callbacks
+                .drain(..)
+            {
+                callback.done(time);
+            }
+        },
+        |_, _, &()| true,
+    );
+}
@@ -0,0 +1,333 @@
+use crate::definitions::*;
+use crate::surface::{location_in_root, registered_id, root_surface};
+use crate::DispatchContext;
+use std::cell::RefCell;
+use std::rc::{Rc, Weak};
+use std::time::{Duration, Instant};
+
+const RESIZE_CONFIGURE_INTERVAL: Duration = Duration::from_millis(16);
+
+/// New geometry of a window resized from `edges` by a pointer moved by `delta`,
+/// the opposite edges stay in place. A zero max size component means unlimited.
+pub fn resize_geometry(
+    edges: ResizeEdge,
+    initial: Rectangle<i32, Logical>,
+    delta: Point<i32, Logical>,
+    min_size: Size<i32, Logical>,
+    max_size: Size<i32, Logical>,
+) -> Rectangle<i32, Logical> {
+    let (left, right, top, bottom) = match edges {
+        ResizeEdge::Top => (false, false, true, false),
+        ResizeEdge::Bottom => (false, false, false, true),
+        ResizeEdge::Left => (true, false, false, false),
+        ResizeEdge::Right => (false, true, false, false),
+        ResizeEdge::TopLeft => (true, false, true, false),
+        ResizeEdge::TopRight => (false, true, true, false),
+        ResizeEdge::BottomLeft => (true, false, false, true),
+        ResizeEdge::BottomRight => (false, true, false, true),
+        _ => (false, false, false, false),
+    };
+    let clamp = |value: i32, min: i32, max: i32| {
+        let value = value.max(min).max(1);
+        if max > 0 {
+            value.min(max.max(min))
+        } else {
+            value
+        }
+    };
+
+    let mut width = initial.size.w;
+    if left {
+        width -= delta.x;
+    } else if right {
+        width += delta.x;
+    }
+    let mut height = initial.size.h;
+    if top {
+        height -= delta.y;
+    } else if bottom {
+        height += delta.y;
+    }
+    let width = clamp(width, min_size.w, max_size.w);
+    let height = clamp(height, min_size.h, max_size.h);
+
+    let mut location = initial.loc;
+    if left {
+        location.x = initial.loc.x + initial.size.w - width;
+    }
+    if top {
+        location.y = initial.loc.y + initial.size.h - height;
+    }
+    Rectangle::from_loc_and_size(location, (width, height))
+}
+
+fn pointer_delta(start: Point<f64, Logical>, location: Point<f64, Logical>) -> Point<i32, Logical> {
+    (
+        (location.x - start.x).round() as i32,
+        (location.y - start.y).round() as i32,
+    )
+        .into()
+}
+
+fn push_request(dispatch_context: &Weak<RefCell<DispatchContext>>, request: WaylandRequest) {
+    if let Some(dispatch_context) = dispatch_context.upgrade() {
+        match dispatch_context.try_borrow_mut() {
+            Ok(mut context) => context.requests.push(request),
+            Err(_) => {
+                log::error!(target: "EWS","Grab event dropped, the dispatch context is in use: {:?}",request)
+            }
+        }
+    }
+}
+
+/// Checks that `serial` is the one of the button press holding the implicit grab
+/// of the seat pointer, and that this press happened on the toplevel or one of its
+/// subsurfaces. Also returns the location of the toplevel when the button was pressed.
+fn grab_start(
+    toplevel: &ToplevelSurface,
+    seat: &WlSeat,
+    serial: Serial,
+) -> Option<(PointerHandle, GrabStartData, SurfaceId, Point<i32, Logical>)> {
+    let surface = toplevel.get_surface()?;
+    let id = registered_id(surface)?;
+    let pointer = Seat::from_resource(seat)?.get_pointer()?;
+    if !pointer.has_grab(serial) {
+        log::debug!(target: "EWS","Ignoring grab request of {:?}: serial {:?} does not match a button press",surface,serial);
+        return None;
+    }
+    let start_data = pointer.grab_start_data()?;
+    match &start_data.focus {
+        Some((focus, location)) if root_surface(focus) == *surface => {
+            // The focus location is the one of the pressed subsurface.
+            let location = *location - location_in_root(focus);
+            Some((pointer, start_data, id, location))
+        }
+        _ => {
+            log::debug!(target: "EWS","Ignoring grab request of {:?}: the button was pressed on another surface",surface);
+            None
+        }
+    }
+}
+
+fn release_grab(handle: &mut PointerInnerHandle<'_>, serial: Serial, time: u32) -> bool {
+    if handle.current_pressed().is_empty() {
+        handle.unset_grab(serial, time);
+        true
+    } else {
+        false
+    }
+}
+
+pub struct MoveGrab {
+    start_data: GrabStartData,
+    dispatch_context: Weak<RefCell<DispatchContext>>,
+    id: SurfaceId,
+    initial_location: Point<i32, Logical>,
+}
+impl PointerGrab for MoveGrab {
+    fn motion(
+        &mut self,
+        handle: &mut PointerInnerHandle<'_>,
+        location: Point<f64, Logical>,
+        _focus: Option<(WlSurface, Point<i32, Logical>)>,
+        serial: Serial,
+        time: u32,
+    ) {
+        // No pointer focus while the window is dragged.
+        handle.motion(location, None, serial, time);
+        let delta = pointer_delta(self.start_data.location, location);
+        push_request(
+            &self.dispatch_context,
+            WaylandRequest::WindowMoved {
+                id: self.id,
+                location: self.initial_location + delta,
+            },
+        );
+    }
+    fn button(
+        &mut self,
+        handle: &mut PointerInnerHandle<'_>,
+        button: u32,
+        state: ButtonState,
+        serial: Serial,
+        time: u32,
+    ) {
+        handle.button(button, state, serial, time);
+        release_grab(handle, serial, time);
+    }
+    fn axis(&mut self, handle: &mut PointerInnerHandle<'_>, details: AxisFrame) {
+        handle.axis(details)
+    }
+    fn start_data(&self) -> &GrabStartData {
+        &self.start_data
+    }
+}
+
+pub struct ResizeGrab {
+    start_data: GrabStartData,
+    dispatch_context: Weak<RefCell<DispatchContext>>,
+    id: SurfaceId,
+    edges: ResizeEdge,
+    initial_geometry: Rectangle<i32, Logical>,
+    min_size: Size<i32, Logical>,
+    max_size: Size<i32, Logical>,
+    geometry: Rectangle<i32, Logical>,
+    last_configure: Option<Instant>,
+    /// The geometry changed during the throttle interval and was not configured yet.
+    configure_pending: bool,
+}
+impl ResizeGrab {
+    fn configure(&mut self, resizing: bool) {
+        let dispatch_context = match self.dispatch_context.upgrade() {
+            Some(dispatch_context) => dispatch_context,
+            None => return,
+        };
+        let mut context = match dispatch_context.try_borrow_mut() {
+            Ok(context) => context,
+            Err(_) => return,
+        };
+        if let Some(window) = context.windows.get_mut(&self.id) {
+            window.set_size(Some(self.geometry.size));
+            window.set_state(SurfaceState::Resizing, resizing);
+            let throttled = self
+                .last_configure
+                .map(|last_configure| last_configure.elapsed() < RESIZE_CONFIGURE_INTERVAL)
+                .unwrap_or(false);
+            if resizing && throttled {
+                self.configure_pending = true;
+                return;
+            }
+            window.schedule_configure();
+            self.last_configure = Some(Instant::now());
+            self.configure_pending = false;
+        }
+    }
+}
+impl PointerGrab for ResizeGrab {
+    fn motion(
+        &mut self,
+        handle: &mut PointerInnerHandle<'_>,
+        location: Point<f64, Logical>,
+        _focus: Option<(WlSurface, Point<i32, Logical>)>,
+        serial: Serial,
+        time: u32,
+    ) {
+        handle.motion(location, None, serial, time);
+        let geometry = resize_geometry(
+            self.edges,
+            self.initial_geometry,
+            pointer_delta(self.start_data.location, location),
+            self.min_size,
+            self.max_size,
+        );
+        if geometry == self.geometry {
+            // A throttled geometry is still sent once the interval is over.
+            if self.configure_pending {
+                self.configure(true);
+            }
+            return;
+        }
+        self.geometry = geometry;
+        self.configure(true);
+        push_request(
+            &self.dispatch_context,
+            WaylandRequest::WindowResized {
+                id: self.id,
+                geometry,
+            },
+        );
+    }
+    fn button(
+        &mut self,
+        handle: &mut PointerInnerHandle<'_>,
+        button: u32,
+        state: ButtonState,
+        serial: Serial,
+        time: u32,
+    ) {
+        handle.button(button, state, serial, time);
+        if release_grab(handle, serial, time) {
+            self.configure(false);
+        }
+    }
+    fn axis(&mut self, handle: &mut PointerInnerHandle<'_>, details: AxisFrame) {
+        handle.axis(details)
+    }
+    fn start_data(&self) -> &GrabStartData {
+        &self.start_data
+    }
+}
+
+pub(crate) fn start_move(
+    dispatch_context: &Rc<RefCell<DispatchContext>>,
+    toplevel: &ToplevelSurface,
+    seat: &WlSeat,
+    serial: Serial,
+) {
+    let (pointer, start_data, id, initial_location) = match grab_start(toplevel, seat, serial) {
+        Some(start) => start,
+        None => return,
+    };
+    pointer.set_grab(
+        MoveGrab {
+            start_data,
+            dispatch_context: Rc::downgrade(dispatch_context),
+            id,
+            initial_location,
+        },
+        serial,
+    );
+}
+
+pub(crate) fn start_resize(
+    dispatch_context: &Rc<RefCell<DispatchContext>>,
+    toplevel: &ToplevelSurface,
+    seat: &WlSeat,
+    serial: Serial,
+    edges: ResizeEdge,
+) {
+    let (pointer, start_data, id, location) = match grab_start(toplevel, seat, serial) {
+        Some(start) => start,
+        None => return,
+    };
+    let surface = match toplevel.get_surface() {
+        Some(surface) => surface,
+        None => return,
+    };
+    let cached = with_states(surface, |surface_data| {
+        let cached = surface_data.cached_state.current::<SurfaceCachedState>();
+        (cached.geometry, cached.min_size, cached.max_size)
+    });
+    let (geometry, min_size, max_size) = match cached {
+        Ok(cached) => cached,
+        Err(_) => return,
+    };
+    let committed_size = dispatch_context
+        .borrow()
+        .windows
+        .get(&id)
+        .and_then(|window| window.committed().and_then(|state| state.size));
+    let size = match geometry.map(|geometry| geometry.size).or(committed_size) {
+        Some(size) => size,
+        None => {
+            log::debug!(target: "EWS","Ignoring resize of {:?}: unknown window size",surface);
+            return;
+        }
+    };
+    let initial_geometry = Rectangle::from_loc_and_size(location, size);
+    pointer.set_grab(
+        ResizeGrab {
+            start_data,
+            dispatch_context: Rc::downgrade(dispatch_context),
+            id,
+            edges,
+            initial_geometry,
+            min_size,
+            max_size,
+            geometry: initial_geometry,
+            last_configure: None,
+            configure_pending: false,
+        },
+        serial,
+    );
+}
@@ -0,0 +1,160 @@
+use crate::buffer::AttachedBuffer;
+use crate::definitions::*;
+#[cfg(feature = "xdg_shell")]
+use crate::window::WindowState;
+
+pub trait WaylandHandler {
+    fn request(&mut self, request: WaylandRequest) {
+        log::debug!(target: "EWS","Unhandled request {:#?}",request);
+    }
+
+    fn client_connected(&mut self, id: ClientId, credentials: Option<ClientCredentials>) {
+        self.request(WaylandRequest::ClientConnected { id, credentials });
+    }
+    fn client_disconnected(&mut self, id: ClientId) {
+        self.request(WaylandRequest::ClientDisconnected { id });
+    }
+
+    fn keyboard_focus(&mut self, seat: Seat, focus: Option<WlSurface>) {
+        self.request(WaylandRequest::Seat {
+            seat,
+            request: SeatRequest::KeaybordFocus(focus),
+        });
+    }
+    fn cursor_image(&mut self, seat: Seat, status: CursorImageStatus) {
+        self.request(WaylandRequest::Seat {
+            seat,
+            request: SeatRequest::CursorImage(status),
+        });
+    }
+
+    fn surface_created(&mut self, id: SurfaceId, surface: WlSurface) {
+        self.request(WaylandRequest::SurfaceCreated { id, surface });
+    }
+    fn surface_role_changed(&mut self, id: SurfaceId, role: &'static str) {
+        self.request(WaylandRequest::SurfaceRoleChanged { id, role });
+    }
+    fn surface_removed(&mut self, id: SurfaceId) {
+        self.request(WaylandRequest::SurfaceRemoved { id });
+    }
+    fn commit(
+        &mut self,
+        surface: WlSurface,
+        buffer: Option<AttachedBuffer>,
+        scale: i32,
+        transform: wl_output::Transform,
+        offset: Point<i32, Logical>,
+    ) {
+        self.request(WaylandRequest::Commit {
+            surface,
+            buffer,
+            scale,
+            transform,
+            offset,
+        });
+    }
+    #[cfg(feature = "subcompositor")]
+    fn surface_tree_changed(&mut self, root: WlSurface) {
+        self.request(WaylandRequest::SurfaceTreeChanged { root });
+    }
+
+    #[cfg(feature = "xdg_shell")]
+    fn new_toplevel(&mut self, surface: ToplevelSurface) {
+        self.xdg_request(XdgRequest::NewToplevel { surface });
+    }
+    #[cfg(feature = "xdg_shell")]
+    fn new_popup(&mut self, surface: PopupSurface, positioner: PositionerState) {
+        self.xdg_request(XdgRequest::NewPopup {
+            surface,
+            positioner,
+        });
+    }
+    #[cfg(feature = "xdg_shell")]
+    fn xdg_request(&mut self, request: XdgRequest) {
+        self.request(WaylandRequest::XdgRequest { request });
+    }
+    #[cfg(feature = "xdg_shell")]
+    fn window_state_applied(&mut self, id: SurfaceId, state: WindowState) {
+        self.request(WaylandRequest::WindowStateApplied { id, state });
+    }
+    #[cfg(feature = "xdg_shell")]
+    fn window_moved(&mut self, id: SurfaceId, location: Point<i32, Logical>) {
+        self.request(WaylandRequest::WindowMoved { id, location });
+    }
+    #[cfg(feature = "xdg_shell")]
+    fn window_resized(&mut self, id: SurfaceId, geometry: Rectangle<i32, Logical>) {
+        self.request(WaylandRequest::WindowResized { id, geometry });
+    }
+
+    /// Called while the client waits for the import result, return `false` to make it fail.
+    #[cfg(feature = "dma_buf")]
+    fn dmabuf_import(&mut self, buffer: &Dmabuf) -> bool {
+        self.request(WaylandRequest::Dmabuf {
+            buffer: buffer.clone(),
+        });
+        true
+    }
+
+    #[cfg(feature = "dnd")]
+    fn dnd(&mut self, dnd: DataDeviceEvent) {
+        self.request(WaylandRequest::Dnd { dnd });
+    }
+    #[cfg(feature = "dnd")]
+    fn dnd_action(&mut self, available: DndAction, preferred: DndAction) -> DndAction {
+        default_action_chooser(available, preferred)
+    }
+}
+
+impl WaylandHandler for Vec<WaylandRequest> {
+    fn request(&mut self, request: WaylandRequest) {
+        self.push(request);
+    }
+}
+
+pub(crate) fn deliver(handler: &mut dyn WaylandHandler, request: WaylandRequest) {
+    match request {
+        WaylandRequest::ClientConnected { id, credentials } => {
+            handler.client_connected(id, credentials)
+        }
+        WaylandRequest::ClientDisconnected { id } => handler.client_disconnected(id),
+        WaylandRequest::Seat {
+            seat,
+            request: SeatRequest::KeaybordFocus(focus),
+        } => handler.keyboard_focus(seat, focus),
+        WaylandRequest::Seat {
+            seat,
+            request: SeatRequest::CursorImage(status),
+        } => handler.cursor_image(seat, status),
+        WaylandRequest::SurfaceCreated { id, surface } => handler.surface_created(id, surface),
+        WaylandRequest::SurfaceRoleChanged { id, role } => handler.surface_role_changed(id, role),
+        WaylandRequest::SurfaceRemoved { id } => handler.surface_removed(id),
+        WaylandRequest::Commit {
+            surface,
+            buffer,
+            scale,
+            transform,
+            offset,
+        } => handler.commit(surface, buffer, scale, transform, offset),
+        #[cfg(feature = "subcompositor")]
+        WaylandRequest::SurfaceTreeChanged { root } => handler.surface_tree_changed(root),
+        #[cfg(feature = "xdg_shell")]
+        WaylandRequest::XdgRequest { request } => match request {
+            XdgRequest::NewToplevel { surface } => handler.new_toplevel(surface),
+            XdgRequest::NewPopup {
+                surface,
+                positioner,
+            } => handler.new_popup(surface, positioner),
+            request => handler.xdg_request(request),
+        },
+        #[cfg(feature = "xdg_shell")]
+        WaylandRequest::WindowStateApplied { id, state } => handler.window_state_applied(id, state),
+        #[cfg(feature = "xdg_shell")]
+        WaylandRequest::WindowMoved { id, location } => handler.window_moved(id, location),
+        #[cfg(feature = "xdg_shell")]
+        WaylandRequest::WindowResized { id, geometry } => handler.window_resized(id, geometry),
+        #[cfg(feature = "dma_buf")]
+        WaylandRequest::Dmabuf { buffer } => handler.request(WaylandRequest::Dmabuf { buffer }),
+        #[cfg(feature = "dnd")]
+        WaylandRequest::Dnd { dnd } => handler.dnd(dnd),
+    }
+}
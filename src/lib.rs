@@ -4,23 +4,188 @@ mod tests;
 pub mod definitions;
 pub use definitions::*;
 
+pub mod buffer;
+pub use buffer::*;
+
+pub mod error;
+pub use error::*;
+
+pub mod client;
+pub use client::*;
+
+pub mod filter;
+pub use filter::*;
+
+pub mod handler;
+pub use handler::*;
+
+#[cfg(feature = "xdg_shell")]
+pub mod configure;
+#[cfg(feature = "xdg_shell")]
+pub use configure::*;
+
+#[cfg(feature = "xdg_shell")]
+pub mod window;
+#[cfg(feature = "xdg_shell")]
+pub use window::*;
+
+#[cfg(feature = "xdg_shell")]
+pub mod grabs;
+#[cfg(feature = "xdg_shell")]
+pub use grabs::*;
+
+#[cfg(feature = "xdg_shell")]
+pub mod popup;
+#[cfg(feature = "xdg_shell")]
+pub use popup::*;
+
+#[cfg(feature = "xdg_shell")]
+pub mod positioner;
+#[cfg(feature = "xdg_shell")]
+pub use positioner::*;
+
+#[cfg(feature = "dma_buf")]
+pub mod dmabuf;
+#[cfg(feature = "dma_buf")]
+pub use dmabuf::*;
+
+mod socket;
+use socket::{ListeningSocket, PollFd};
+
+mod damage;
+
+mod frame;
+
+mod pixel;
+
+pub mod capture;
+pub use capture::*;
+
+#[cfg(feature = "software_renderer")]
+pub mod software_renderer;
+#[cfg(feature = "software_renderer")]
+pub use software_renderer::*;
+
+#[cfg(feature = "presentation_time")]
+mod presentation;
+
+mod surface;
+#[cfg(feature = "xdg_shell")]
+use surface::registered_id;
+use surface::{register_surface, set_surface_kind, SurfaceRegistry};
+
+#[cfg(feature = "subcompositor")]
+pub mod tree;
+#[cfg(feature = "subcompositor")]
+pub use tree::*;
+
+#[cfg(feature = "async")]
+pub mod stream;
+#[cfg(feature = "async")]
+pub use stream::*;
+
 use std::cell::Cell;
 use std::cell::RefCell;
 use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::os::unix::io::{AsRawFd, IntoRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command};
 use std::rc::Rc;
 use std::time::Duration;
 
 #[derive(Debug)]
 pub struct DispatchContext {
-    surfaces: Vec<(usize, WlSurface)>,
+    surfaces: SurfaceRegistry,
+    clients: ClientRegistry,
     requests: Vec<WaylandRequest>,
+    handler: Option<*mut (dyn WaylandHandler + 'static)>,
+    #[cfg(feature = "dma_buf")]
+    dmabuf_validator: Option<Box<dyn DmabufValidator>>,
+    #[cfg(feature = "xdg_shell")]
+    configure_policy: Option<Box<dyn ConfigurePolicy>>,
+    #[cfg(feature = "xdg_shell")]
+    configures: configure::ConfigureScheduler,
+    #[cfg(feature = "xdg_shell")]
+    windows: HashMap<SurfaceId, Window>,
+    #[cfg(feature = "xdg_shell")]
+    builtin_grabs: bool,
+    #[cfg(feature = "xdg_shell")]
+    popups: PopupManager,
 }
 impl DispatchContext {
     pub fn new() -> Self {
-        let surfaces = Vec::new();
+        let surfaces = SurfaceRegistry::new();
+        let clients = ClientRegistry::new();
         let requests = Vec::new();
+        let handler = None;
+
+        Self {
+            surfaces,
+            clients,
+            requests,
+            handler,
+            #[cfg(feature = "dma_buf")]
+            dmabuf_validator: None,
+            #[cfg(feature = "xdg_shell")]
+            configure_policy: None,
+            #[cfg(feature = "xdg_shell")]
+            configures: configure::ConfigureScheduler::default(),
+            #[cfg(feature = "xdg_shell")]
+            windows: HashMap::new(),
+            #[cfg(feature = "xdg_shell")]
+            builtin_grabs: true,
+            #[cfg(feature = "xdg_shell")]
+            popups: PopupManager::default(),
+        }
+    }
+}
 
-        Self { surfaces, requests }
+/// Gives access to the handler of the running `dispatch_with`, after forwarding
+/// it the queued requests so that their order is preserved.
+fn with_handler<T>(
+    dispatch_context: &Rc<RefCell<DispatchContext>>,
+    f: impl FnOnce(&mut dyn WaylandHandler) -> T,
+) -> Option<T> {
+    let handler = dispatch_context.borrow().handler?;
+    // Safety: the pointer is only set while `dispatch_with` holds the exclusive borrow
+    // of the handler, and handler methods never run re-entrantly because anything
+    // produced while one of them is running is queued instead.
+    let handler = unsafe { &mut *handler };
+    deliver_queued(dispatch_context, handler);
+    Some(f(handler))
+}
+
+/// Clears the handler pointer set by `dispatch_with`, also when a callback unwinds,
+/// so that it never outlives the borrow it was taken from.
+struct HandlerGuard(Rc<RefCell<DispatchContext>>);
+impl Drop for HandlerGuard {
+    fn drop(&mut self) {
+        match self.0.try_borrow_mut() {
+            Ok(mut context) => context.handler = None,
+            Err(_) => {
+                // Only possible while unwinding out of a borrow, better abort than
+                // leave a dangling pointer behind.
+                log::error!(target: "EWS","Dispatch context still borrowed when clearing the handler");
+                std::process::abort();
+            }
+        }
+    }
+}
+
+fn deliver_queued(
+    dispatch_context: &Rc<RefCell<DispatchContext>>,
+    handler: &mut dyn WaylandHandler,
+) {
+    loop {
+        let requests: Vec<_> = dispatch_context.borrow_mut().requests.drain(..).collect();
+        if requests.is_empty() {
+            break;
+        }
+        for request in requests {
+            deliver(handler, request);
+        }
     }
 }
 
@@ -28,8 +193,14 @@ impl DispatchContext {
 pub struct EmbeddedWaylandServer {
     dispatch_context: Rc<RefCell<DispatchContext>>,
     display: Display,
+    socket: Option<ListeningSocket>,
+    poll_fd: Option<PollFd>,
+    global_policy: GlobalPolicy,
+    buffer_hold_warning: Option<Duration>,
+    hidden_frame_interval: Duration,
 
     compositor_global: Global<WlCompositor>,
+    #[cfg(feature = "subcompositor")]
     subcompositor_global: Global<WlSubcompositor>,
 
     seat_globals: HashMap<usize, (Seat, Global<WlSeat>)>,
@@ -51,89 +222,218 @@ pub struct EmbeddedWaylandServer {
 
     #[cfg(feature = "explicit_synchronization")]
     explicit_synchronization_global: Global<ZwpLinuxExplicitSynchronizationV1>,
+
+    #[cfg(feature = "presentation_time")]
+    presentation_global: Global<WpPresentation>,
 }
 
 impl EmbeddedWaylandServer {
-    pub fn new(parameters: Parameters) -> Self {
+    pub fn new(parameters: Parameters) -> Result<Self, ServerError> {
         let mut display = Display::new();
-        display
-            .add_socket_auto()
-            .expect("Failed to bind wayland socket");
+        let socket = match parameters.socket {
+            SocketMode::Auto => Some(ListeningSocket::bind_auto()),
+            SocketMode::Named(name) => Some(ListeningSocket::bind(name)),
+            SocketMode::Listener(listener) => Some(ListeningSocket::from_listener(listener)),
+            SocketMode::None => None,
+        }
+        .transpose()
+        .map_err(ServerError::SocketBind)?;
+        let poll_fd = match &socket {
+            Some(socket) => Some(
+                PollFd::new(&[display.get_poll_fd(), socket.as_raw_fd()])
+                    .map_err(ServerError::SocketBind)?,
+            ),
+            None => None,
+        };
+        if let Some(name) = socket.as_ref().and_then(ListeningSocket::name) {
+            log::info!(target: "EWS","Listening on wayland socket {:?}",name);
+        }
 
         let dispatch_context = Rc::new(RefCell::new(DispatchContext::new()));
+        let global_policy = GlobalPolicy::new(parameters.global_filter);
 
         let seat_globals = HashMap::new();
         let output_globals = HashMap::new();
 
+        let compositor_policy = global_policy.clone();
         let (compositor_global, subcompositor_global) =
             smithay::wayland::compositor::compositor_init(
                 &mut display,
-                |surface, mut dispatch_data| {
+                move |surface, mut dispatch_data| {
+                    if !compositor_policy.enforce(surface.as_ref().client(), WlCompositor::NAME) {
+                        return;
+                    }
                     let dispatch_context: &mut Rc<RefCell<DispatchContext>> =
                         dispatch_data.get().unwrap();
-                    dispatch_context
-                        .borrow_mut()
-                        .requests
-                        .push(WaylandRequest::Commit { surface });
+                    let id = register_surface(dispatch_context, &surface);
+                    // Smithay applies the cached state of a synchronized subsurface when
+                    // its parent commits, it is resolved with the parent tree then.
+                    if is_sync_subsurface(&surface) {
+                        return;
+                    }
+                    let commits = buffer::commit_tree(&surface);
+                    let subsurface = commits.iter().any(|(node, _commit_state)| {
+                        with_states(node, |surface_data| surface_data.role == Some("subsurface"))
+                            .unwrap_or(false)
+                    });
+                    let used_globals = commits
+                        .iter()
+                        .filter_map(|(_node, commit_state)| commit_state.buffer.as_ref())
+                        .filter_map(AttachedBuffer::global_interface)
+                        .chain(subsurface.then(|| WlSubcompositor::NAME));
+                    for interface in used_globals {
+                        if !compositor_policy.enforce(surface.as_ref().client(), interface) {
+                            return;
+                        }
+                    }
+                    let mut context = dispatch_context.borrow_mut();
+                    #[cfg(feature = "subcompositor")]
+                    if let Some(root) = tree::layout_changed(&surface) {
+                        context
+                            .requests
+                            .push(WaylandRequest::SurfaceTreeChanged { root });
+                    }
+                    for (surface, commit_state) in commits {
+                        context.requests.push(WaylandRequest::Commit {
+                            surface,
+                            buffer: commit_state.buffer,
+                            scale: commit_state.scale,
+                            transform: commit_state.transform,
+                            offset: commit_state.offset,
+                        });
+                    }
+                    #[cfg(feature = "xdg_shell")]
+                    if let Some((id, state)) = id.and_then(|id| {
+                        let window = context.windows.get_mut(&id)?;
+                        Some((id, window.commit()?))
+                    }) {
+                        context
+                            .requests
+                            .push(WaylandRequest::WindowStateApplied { id, state });
+                    }
                 },
                 None,
             );
+        #[cfg(not(feature = "subcompositor"))]
+        subcompositor_global.destroy();
 
         #[cfg(feature = "shm")]
         let shm_global =
             smithay::wayland::shm::init_shm_global(&mut display, parameters.shm_formats, None);
 
+        #[cfg(feature = "xdg_shell")]
+        let xdg_shell_policy = global_policy.clone();
         #[cfg(feature = "xdg_shell")]
         let (xdg_shell_state, xdg_wm_base_global) = smithay::wayland::shell::xdg::xdg_shell_init(
             &mut display,
-            |request, mut dispatch_data| {
+            move |request, mut dispatch_data| {
+                let client = match &request {
+                    XdgRequest::NewToplevel { surface } => surface
+                        .get_surface()
+                        .and_then(|surface| surface.as_ref().client()),
+                    XdgRequest::NewPopup { surface, .. } => surface
+                        .get_surface()
+                        .and_then(|surface| surface.as_ref().client()),
+                    _ => None,
+                };
+                if !xdg_shell_policy.enforce(client, XdgWmBase::NAME) {
+                    return;
+                }
                 let new_surface = match &request {
-                    XdgRequest::NewToplevel { surface } => {
-                        let kind = SurfaceKind::from(surface.clone());
-                        surface.get_surface().map(|surface|{
-                        let id: u32 = SERIAL_COUNTER.next_serial().into();
-                        let result = with_states(surface,|surface_data|{
-                            surface_data.data_map.insert_if_missing(||SurfaceId::from(id));
-                            surface_data.data_map.insert_if_missing(||kind);
-                        });
-
-                        match result {
-                            Ok(_)=>log::info!(target: "EWS","New toplevel surface {:#?}, assigned id {}",surface,id),
-                            Err(err)=>log::error!(target: "EWS","Error while setting NewPopup surface id: {:#?}",err)
-                        }
-                        (id as usize,surface.clone())
-                    })
-                    }
-                    XdgRequest::NewPopup {
-                        surface,
-                        positioner: _,
-                    } => {
-                        let kind = SurfaceKind::from(surface.clone());
-                        surface.get_surface().map(|surface|{
-                        let id: u32 = SERIAL_COUNTER.next_serial().into();
-                        let result = with_states(surface,|surface_data|{
-                            surface_data.data_map.insert_if_missing(||SurfaceId::from(id));
-                            surface_data.data_map.insert_if_missing(||kind);
-                        });
-                        match result {
-                            Ok(_)=> log::info!(target: "EWS","New popup surface {:#?}, assigned id {}",surface,id),
-                            Err(err)=>log::error!(target: "EWS","Error while setting NewPopup surface id: {:#?}",err)
-                        }
-                        (id as usize,surface.clone())
-                    })
-                    }
+                    XdgRequest::NewToplevel { surface } => surface
+                        .get_surface()
+                        .map(|wl_surface| (wl_surface.clone(), SurfaceKind::from(surface.clone()))),
+                    XdgRequest::NewPopup { surface, .. } => surface
+                        .get_surface()
+                        .map(|wl_surface| (wl_surface.clone(), SurfaceKind::from(surface.clone()))),
                     _ => None,
                 };
 
                 let dispatch_context: &mut Rc<RefCell<DispatchContext>> =
                     dispatch_data.get().unwrap();
-                let mut context = dispatch_context.borrow_mut();
-                new_surface.map(|surface| {
-                    context.surfaces.push(surface);
+                let new_id = new_surface.and_then(|(surface, kind)| {
+                    set_surface_kind(&surface, kind);
+                    register_surface(dispatch_context, &surface)
                 });
+                let mut context = dispatch_context.borrow_mut();
+                let grab = match &request {
+                    XdgRequest::Move {
+                        surface,
+                        seat,
+                        serial,
+                    } if context.builtin_grabs => {
+                        Some((surface.clone(), seat.clone(), *serial, None))
+                    }
+                    XdgRequest::Resize {
+                        surface,
+                        seat,
+                        serial,
+                        edges,
+                    } if context.builtin_grabs => {
+                        Some((surface.clone(), seat.clone(), *serial, Some(*edges)))
+                    }
+                    _ => None,
+                };
+                let popup_grab = match &request {
+                    XdgRequest::Grab {
+                        surface,
+                        seat,
+                        serial,
+                    } => Some((
+                        context.popups.clone(),
+                        surface.clone(),
+                        seat.clone(),
+                        *serial,
+                    )),
+                    _ => None,
+                };
+                match &request {
+                    XdgRequest::NewToplevel { surface } => {
+                        if let Some(id) = new_id {
+                            context.windows.insert(id, Window::new(surface.clone()));
+                        }
+                        context
+                            .configures
+                            .schedule(surface.clone().into(), ConfigureReason::InitialMap)
+                    }
+                    XdgRequest::NewPopup { surface, .. } => context
+                        .configures
+                        .schedule(surface.clone().into(), ConfigureReason::InitialMap),
+                    XdgRequest::Maximize { surface }
+                    | XdgRequest::UnMaximize { surface }
+                    | XdgRequest::Fullscreen { surface, .. }
+                    | XdgRequest::UnFullscreen { surface } => context
+                        .configures
+                        .schedule(surface.clone().into(), ConfigureReason::StateChange),
+                    XdgRequest::AckConfigure { surface, configure } => {
+                        configure::acked(surface, configure);
+                        if let Configure::Toplevel(configure) = configure {
+                            if let Some(window) =
+                                registered_id(surface).and_then(|id| context.windows.get_mut(&id))
+                            {
+                                window.ack(configure.serial, &configure.state);
+                            }
+                        }
+                    }
+                    _ => (),
+                }
                 context
                     .requests
                     .push(WaylandRequest::XdgRequest { request });
+                drop(context);
+
+                match grab {
+                    Some((toplevel, seat, serial, None)) => {
+                        grabs::start_move(dispatch_context, &toplevel, &seat, serial)
+                    }
+                    Some((toplevel, seat, serial, Some(edges))) => {
+                        grabs::start_resize(dispatch_context, &toplevel, &seat, serial, edges)
+                    }
+                    None => (),
+                }
+                if let Some((popups, popup, seat, serial)) = popup_grab {
+                    popups.grab(&popup, &seat, serial);
+                }
             },
             None,
         );
@@ -143,30 +443,64 @@ impl EmbeddedWaylandServer {
             &mut display,
             parameters.drm_formats,
             |dmabuf, mut dispatch_data| {
-                let buffer = dmabuf.clone();
                 let dispatch_context: &mut Rc<RefCell<DispatchContext>> =
                     dispatch_data.get().unwrap();
-                dispatch_context
+                let valid = dispatch_context
                     .borrow_mut()
-                    .requests
-                    .push(WaylandRequest::Dmabuf { buffer });
-                true
+                    .dmabuf_validator
+                    .as_mut()
+                    .map(|validator| validator.validate(dmabuf))
+                    .unwrap_or(true);
+                if !valid {
+                    log::warn!(target: "EWS","Dmabuf import of {:?} rejected by the validator",dmabuf.format());
+                    return false;
+                }
+                match with_handler(dispatch_context, |handler| handler.dmabuf_import(dmabuf)) {
+                    Some(result) => result,
+                    None => {
+                        let buffer = dmabuf.clone();
+                        dispatch_context
+                            .borrow_mut()
+                            .requests
+                            .push(WaylandRequest::Dmabuf { buffer });
+                        true
+                    }
+                }
             },
             None,
         );
 
         #[cfg(feature = "dnd")]
         let dnd_global = {
+            let action_context = dispatch_context.clone();
             let dispatch_context = dispatch_context.clone();
+            let global_policy = global_policy.clone();
             init_data_device(
                 &mut display,
                 move |dnd| {
+                    let client = match &dnd {
+                        DataDeviceEvent::NewSelection(source) => {
+                            source.as_ref().and_then(|source| source.as_ref().client())
+                        }
+                        DataDeviceEvent::DnDStarted { source, .. } => {
+                            source.as_ref().and_then(|source| source.as_ref().client())
+                        }
+                        _ => None,
+                    };
+                    if !global_policy.enforce(client, WlDataDeviceManager::NAME) {
+                        return;
+                    }
                     dispatch_context
                         .borrow_mut()
                         .requests
                         .push(WaylandRequest::Dnd { dnd });
                 },
-                default_action_chooser,
+                move |available, preferred| {
+                    with_handler(&action_context, |handler| {
+                        handler.dnd_action(available, preferred)
+                    })
+                    .unwrap_or_else(|| default_action_chooser(available, preferred))
+                },
                 None,
             )
         };
@@ -175,8 +509,22 @@ impl EmbeddedWaylandServer {
         let explicit_synchronization_global =
             init_explicit_synchronization_global(&mut display, None);
 
-        Self {
+        #[cfg(feature = "presentation_time")]
+        let presentation_global = presentation::init_presentation_global(
+            &mut display,
+            parameters
+                .presentation_clock_id
+                .unwrap_or(libc::CLOCK_MONOTONIC as u32),
+            &global_policy,
+        );
+
+        Ok(Self {
             display,
+            socket,
+            poll_fd,
+            global_policy,
+            buffer_hold_warning: None,
+            hidden_frame_interval: Duration::from_secs(1),
 
             dispatch_context,
 
@@ -184,6 +532,7 @@ impl EmbeddedWaylandServer {
             output_globals,
 
             compositor_global,
+            #[cfg(feature = "subcompositor")]
             subcompositor_global,
 
             #[cfg(feature = "shm")]
@@ -202,31 +551,322 @@ impl EmbeddedWaylandServer {
 
             #[cfg(feature = "explicit_synchronization")]
             explicit_synchronization_global,
+
+            #[cfg(feature = "presentation_time")]
+            presentation_global,
+        })
+    }
+
+    pub fn dispatch(&mut self) -> Result<Vec<WaylandRequest>, ServerError> {
+        self.dispatch_timeout(Duration::from_millis(0))
+    }
+    pub fn dispatch_timeout(
+        &mut self,
+        timeout: Duration,
+    ) -> Result<Vec<WaylandRequest>, ServerError> {
+        self.wait(Some(timeout))?;
+        self.dispatch_pending()
+    }
+    pub fn dispatch_blocking(&mut self) -> Result<Vec<WaylandRequest>, ServerError> {
+        self.wait(None)?;
+        self.dispatch_pending()
+    }
+
+    /// Sends the events queued since the last dispatch, like frame callbacks or focus
+    /// changes, which would otherwise wait until a client writes something.
+    pub fn flush_clients(&mut self) {
+        self.display.flush_clients(&mut self.dispatch_context);
+    }
+
+    #[cfg(feature = "async")]
+    pub(crate) fn has_queued_requests(&self) -> bool {
+        !self.dispatch_context.borrow().requests.is_empty()
+    }
+
+    fn wait(&mut self, timeout: Option<Duration>) -> Result<(), ServerError> {
+        self.flush_clients();
+        if !self.dispatch_context.borrow().requests.is_empty() {
+            return Ok(());
         }
+        let timeout = match timeout {
+            // Rounded up, a sub-millisecond timeout must not turn into a busy poll.
+            Some(timeout) => ((timeout.as_nanos() + 999_999) / 1_000_000)
+                .min(libc::c_int::MAX as u128) as libc::c_int,
+            None => -1,
+        };
+        if timeout == 0 {
+            return Ok(());
+        }
+        let mut poll_fd = libc::pollfd {
+            fd: self.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        if unsafe { libc::poll(&mut poll_fd, 1, timeout) } < 0 {
+            let err = std::io::Error::last_os_error();
+            if err.kind() != std::io::ErrorKind::Interrupted {
+                return Err(ServerError::Dispatch(err));
+            }
+        }
+        Ok(())
     }
+    fn dispatch_pending(&mut self) -> Result<Vec<WaylandRequest>, ServerError> {
+        let mut requests = Vec::new();
+        self.dispatch_with(&mut requests)?;
+        Ok(requests)
+    }
+    pub fn dispatch_with(&mut self, handler: &mut impl WaylandHandler) -> Result<(), ServerError> {
+        self.accept_clients()?;
 
-    pub fn dispatch(&mut self) -> Vec<WaylandRequest> {
-        match self
+        let handler: &mut dyn WaylandHandler = handler;
+        let handler_ptr: *mut (dyn WaylandHandler + '_) = &mut *handler;
+        self.dispatch_context.borrow_mut().handler = Some(unsafe {
+            std::mem::transmute::<*mut (dyn WaylandHandler + '_), *mut (dyn WaylandHandler + 'static)>(
+                handler_ptr,
+            )
+        });
+        let handler_guard = HandlerGuard(self.dispatch_context.clone());
+        let result = self
             .display
-            .dispatch(Duration::from_millis(0), &mut self.dispatch_context)
-        {
-            Ok(()) => {}
-            Err(_err) => {}
+            .dispatch(Duration::from_millis(0), &mut self.dispatch_context);
+        drop(handler_guard);
+        result.map_err(ServerError::Dispatch)?;
+        #[cfg(feature = "xdg_shell")]
+        self.send_configures();
+
+        if let Some(threshold) = self.buffer_hold_warning {
+            let context = self.dispatch_context.borrow();
+            for (_id, surface) in context.surfaces.iter() {
+                buffer::warn_held_too_long(surface, threshold);
+            }
         }
-        self.display.flush_clients(&mut self.dispatch_context);
 
-        let mut removed = Vec::new();
+        deliver_queued(&self.dispatch_context, handler);
+        // Also sends what the handler replied.
+        self.flush_clients();
+        let mut context = self.dispatch_context.borrow_mut();
+        context.surfaces.release_removed();
+        context.clients.release_removed();
+        Ok(())
+    }
+
+    pub fn socket_name(&self) -> Option<&OsStr> {
+        self.socket.as_ref().and_then(ListeningSocket::name)
+    }
+
+    fn accept_clients(&mut self) -> Result<(), ServerError> {
+        while let Some(stream) = match &self.socket {
+            Some(socket) => socket.accept().map_err(ServerError::Dispatch)?,
+            None => None,
+        } {
+            let credentials = peer_credentials(stream.as_raw_fd());
+            self.insert_client(stream, ClientOrigin::External, credentials);
+        }
+        Ok(())
+    }
+    fn insert_client(
+        &mut self,
+        stream: UnixStream,
+        origin: ClientOrigin,
+        credentials: Option<ClientCredentials>,
+    ) -> ClientId {
+        let client = unsafe {
+            self.display
+                .create_client(stream.into_raw_fd(), &mut self.dispatch_context)
+        };
+
         let mut context = self.dispatch_context.borrow_mut();
-        context.surfaces.retain(|surface| {
-            if !surface.1.as_ref().is_alive() {
-                removed.push(WaylandRequest::SurfaceRemoved { id: surface.0 });
-                false
-            } else {
-                true
+        let id = context
+            .clients
+            .register(&client, origin, credentials.clone());
+        let weak_context = Rc::downgrade(&self.dispatch_context);
+        client.add_destructor(Filter::new(move |data_map: Arc<UserDataMap>, _, _| {
+            let id = match data_map.get::<ClientId>() {
+                Some(id) => *id,
+                None => return,
+            };
+            if let Some(dispatch_context) = weak_context.upgrade() {
+                match dispatch_context.try_borrow_mut() {
+                    Ok(mut context) => {
+                        context.clients.mark_removed(id);
+                        context
+                            .requests
+                            .push(WaylandRequest::ClientDisconnected { id });
+                    }
+                    Err(_) => {
+                        log::error!(target: "EWS","Client {:?} disconnected while the dispatch context is in use",id)
+                    }
+                }
             }
+            log::info!(target: "EWS","Client {:?} disconnected",id);
+        }));
+        log::info!(target: "EWS","Client {:?} connected ({:?}), credentials: {:?}",id,origin,credentials);
+        context
+            .requests
+            .push(WaylandRequest::ClientConnected { id, credentials });
+        id
+    }
+
+    pub fn create_client(&mut self) -> Result<(ClientId, UnixStream), ServerError> {
+        let (server_end, client_end) = UnixStream::pair().map_err(ServerError::ClientSocket)?;
+        // SO_PEERCRED would report the compositor itself, whoever ends up using `client_end`.
+        let id = self.insert_client(server_end, ClientOrigin::Internal, None);
+        Ok((id, client_end))
+    }
+    pub fn spawn_client(&mut self, mut command: Command) -> Result<(ClientId, Child), ServerError> {
+        let (server_end, client_end) = UnixStream::pair().map_err(ServerError::ClientSocket)?;
+        let fd = client_end.as_raw_fd();
+        command.env("WAYLAND_SOCKET", fd.to_string());
+        unsafe {
+            command.pre_exec(move || {
+                let flags = libc::fcntl(fd, libc::F_GETFD);
+                if flags == -1 || libc::fcntl(fd, libc::F_SETFD, flags & !libc::FD_CLOEXEC) == -1 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        let child = command.spawn().map_err(ServerError::Spawn)?;
+        drop(client_end);
+
+        // The socketpair was created by this process, so SO_PEERCRED reports our own pid.
+        let credentials = peer_credentials(server_end.as_raw_fd()).map(|credentials| {
+            ClientCredentials::new(child.id() as i32, credentials.uid, credentials.gid)
         });
-        context.requests.append(&mut removed);
-        context.requests.drain(..).collect()
+        let id = self.insert_client(server_end, ClientOrigin::Internal, credentials);
+        Ok((id, child))
+    }
+    pub fn client_origin(&self, id: ClientId) -> Option<ClientOrigin> {
+        self.dispatch_context.borrow().clients.origin(id)
+    }
+    pub fn client_of_surface(&self, surface: &WlSurface) -> Option<ClientId> {
+        surface
+            .as_ref()
+            .client()
+            .and_then(|client| client_id(&client))
+    }
+    pub fn client_credentials(&self, id: ClientId) -> Option<ClientCredentials> {
+        self.dispatch_context
+            .borrow()
+            .clients
+            .get(id)
+            .and_then(client_credentials)
+            .cloned()
+    }
+    pub fn surface_credentials(&self, surface: &WlSurface) -> Option<ClientCredentials> {
+        surface
+            .as_ref()
+            .client()
+            .and_then(|client| client_credentials(&client).cloned())
+    }
+    /// Whether the global filter lets the client use `interface`, see `GlobalFilter`
+    /// for which globals are actually hidden and which are only enforced on use.
+    pub fn is_global_allowed(&self, id: ClientId, interface: &str) -> bool {
+        self.dispatch_context
+            .borrow()
+            .clients
+            .get(id)
+            .map(|client| self.global_policy.allows(client, interface))
+            .unwrap_or(false)
+    }
+    pub fn list_clients(&self) -> Vec<ClientId> {
+        self.dispatch_context.borrow().clients.ids().collect()
+    }
+    pub fn kill_client(&mut self, id: ClientId) -> Result<(), ServerError> {
+        let client = self
+            .dispatch_context
+            .borrow()
+            .clients
+            .get(id)
+            .cloned()
+            .ok_or(ServerError::UnknownClient(id))?;
+        client.kill();
+        Ok(())
+    }
+
+    #[cfg(feature = "dma_buf")]
+    pub fn set_dmabuf_validator(&mut self, validator: impl DmabufValidator + 'static) {
+        self.dispatch_context.borrow_mut().dmabuf_validator = Some(Box::new(validator));
+    }
+
+    pub fn surface(&self, id: SurfaceId) -> Option<WlSurface> {
+        self.dispatch_context.borrow().surfaces.get(id).cloned()
+    }
+
+    /// Releases the current buffer of the surface early, once its content has been
+    /// copied or uploaded, instead of waiting for the client to replace it.
+    pub fn release_buffer(&self, id: SurfaceId) -> Result<(), ServerError> {
+        let surface = self.surface(id).ok_or(ServerError::UnknownSurface(id))?;
+        with_states(&surface, buffer::release_current)?;
+        Ok(())
+    }
+
+    /// Drains the damage accumulated by the commits of the surface and its subsurfaces,
+    /// in logical coordinates relative to the surface.
+    pub fn take_damage(
+        &mut self,
+        id: SurfaceId,
+    ) -> Result<Vec<Rectangle<i32, Logical>>, ServerError> {
+        let surface = self.surface(id).ok_or(ServerError::UnknownSurface(id))?;
+        Ok(damage::take_tree_damage(&surface))
+    }
+
+    /// Combines the damage of the surfaces and their subsurfaces placed at the given
+    /// locations, clipped to the output geometry. The damage is left in place so that
+    /// other outputs can query it, `take_damage` on each root surface clears it once
+    /// everything is repainted.
+    pub fn output_damage(
+        &self,
+        output_geometry: Rectangle<i32, Logical>,
+        surfaces: impl IntoIterator<Item = (SurfaceId, Point<i32, Logical>)>,
+    ) -> Vec<Rectangle<i32, Logical>> {
+        surfaces
+            .into_iter()
+            .filter_map(|(id, location)| Some((self.surface(id)?, location)))
+            .flat_map(|(surface, location)| damage::tree_damage(&surface, location))
+            .filter_map(|rect| damage::clip(rect, output_geometry))
+            .collect()
+    }
+
+    /// Renders the surfaces that entered the output, `placements` gives their location
+    /// in the output from bottom to top, surfaces missing from it are not drawn.
+    #[cfg(feature = "software_renderer")]
+    pub fn render_output(
+        &self,
+        output_id: usize,
+        renderer: &SoftwareRenderer,
+        size: Size<i32, Logical>,
+        placements: impl IntoIterator<Item = (SurfaceId, Point<i32, Logical>)>,
+    ) -> Result<RgbaImage, ServerError> {
+        self.get_output(output_id)?;
+        let surfaces = placements
+            .into_iter()
+            .filter_map(|(id, location)| Some((self.surface(id)?, location)))
+            .filter(|(surface, _location)| frame::outputs(surface).contains(&output_id));
+        Ok(renderer.render(size, surfaces))
+    }
+
+    pub fn capture_surface(&self, id: SurfaceId) -> Result<Option<RgbaImage>, ServerError> {
+        let surface = self.surface(id).ok_or(ServerError::UnknownSurface(id))?;
+        Ok(capture_surface(&surface))
+    }
+    #[cfg(feature = "software_renderer")]
+    pub fn capture_surface_tree(&self, id: SurfaceId) -> Result<Option<RgbaImage>, ServerError> {
+        let surface = self.surface(id).ok_or(ServerError::UnknownSurface(id))?;
+        Ok(capture_surface_tree(&surface))
+    }
+
+    /// Logs a warning for buffers kept unreleased longer than `threshold` after being
+    /// attached, disabled by default. An idle surface legitimately keeps its current
+    /// buffer, so this is meant for compositors that copy or upload the content and
+    /// then call `release_buffer`.
+    pub fn set_buffer_hold_warning(&mut self, threshold: Option<Duration>) {
+        self.buffer_hold_warning = threshold;
+    }
+
+    #[cfg(feature = "subcompositor")]
+    pub fn surface_tree(&self, id: SurfaceId) -> Option<SurfaceTree> {
+        self.surface(id).map(|surface| SurfaceTree::new(&surface))
     }
 
     pub fn create_seat(&mut self, id: usize, name: impl Into<String>) {
@@ -239,101 +879,121 @@ impl EmbeddedWaylandServer {
         seat.0.user_data().insert_if_missing(|| cursor_surface);
         self.seat_globals.insert(id, seat);
     }
-    pub fn destroy_seat(&mut self, id: usize) {
-        self.seat_globals.remove(&id);
+    pub fn destroy_seat(&mut self, id: usize) -> Result<(), ServerError> {
+        self.seat_globals
+            .remove(&id)
+            .map(|_| ())
+            .ok_or(ServerError::UnknownSeat(id))
     }
     pub fn list_seats(&self) -> impl Iterator<Item = &Seat> {
         self.seat_globals.values().map(|(seat, _global)| seat)
     }
 
-    pub fn add_keyboard(&mut self, seat_id: usize, repeat_delay: i32, repeat_rate: i32) {
-        if let Some((seat, _seat_global)) = self.seat_globals.get_mut(&seat_id) {
-            if seat.get_keyboard().is_none() {
-                let dispatch_context = self.dispatch_context.clone();
+    pub fn add_keyboard(
+        &mut self,
+        seat_id: usize,
+        repeat_delay: i32,
+        repeat_rate: i32,
+    ) -> Result<(), ServerError> {
+        let (seat, _seat_global) = self
+            .seat_globals
+            .get_mut(&seat_id)
+            .ok_or(ServerError::UnknownSeat(seat_id))?;
+        if seat.get_keyboard().is_none() {
+            let dispatch_context = self.dispatch_context.clone();
 
-                seat.add_keyboard(
-                    XkbConfig::default(),
-                    repeat_delay,
-                    repeat_rate,
-                    move |seat, focus| {
-                        dispatch_context
-                            .borrow_mut()
-                            .requests
-                            .push(WaylandRequest::Seat {
-                                seat: seat.clone(),
-                                request: SeatRequest::KeaybordFocus(focus.cloned()),
-                            });
-                    },
-                )
-                .unwrap();
-            }
+            seat.add_keyboard(
+                XkbConfig::default(),
+                repeat_delay,
+                repeat_rate,
+                move |seat, focus| {
+                    dispatch_context
+                        .borrow_mut()
+                        .requests
+                        .push(WaylandRequest::Seat {
+                            seat: seat.clone(),
+                            request: SeatRequest::KeaybordFocus(focus.cloned()),
+                        });
+                },
+            )?;
         }
+        Ok(())
     }
-    pub fn del_keyboard(&mut self, seat_id: usize) {
-        if let Some((seat, _seat_global)) = self.seat_globals.get_mut(&seat_id) {
-            seat.remove_keyboard();
-        }
+    pub fn del_keyboard(&mut self, seat_id: usize) -> Result<(), ServerError> {
+        let (seat, _seat_global) = self
+            .seat_globals
+            .get_mut(&seat_id)
+            .ok_or(ServerError::UnknownSeat(seat_id))?;
+        seat.remove_keyboard();
+        Ok(())
     }
-    pub fn get_keyboard(&self, seat_id: usize) -> Option<KeyboardHandle> {
+    pub fn get_keyboard(&self, seat_id: usize) -> Result<Option<KeyboardHandle>, ServerError> {
         self.seat_globals
             .get(&seat_id)
             .map(|seat| seat.0.get_keyboard())
-            .flatten()
-    }
-
-    pub fn add_cursor(&mut self, seat_id: usize) {
-        if let Some((seat, _seat_global)) = self.seat_globals.get_mut(&seat_id) {
-            if seat.get_pointer().is_none() {
-                let dispatch_context = self.dispatch_context.clone();
-                let seat_cloned = seat.clone();
-                seat.add_pointer(move |cursor_image_status| {
-                    let seat_cloned = &seat_cloned;
-                    match &cursor_image_status {
-                        CursorImageStatus::Image(surface) => {
-                            let id: u32 = SERIAL_COUNTER.next_serial().into();
-                            let result = with_states(&surface, |surface_data| {
-                                surface_data
-                                    .data_map
-                                    .insert_if_missing(|| SurfaceId::from(id));
-                                surface_data
-                                    .data_map
-                                    .insert_if_missing(|| SurfaceKind::Cursor);
-                            });
-                            match result {
-                                Ok(_) => (),
-                                Err(err) => {
-                                    println!("Error while setting NewPopup surface id: {:#?}", err)
-                                }
-                            }
-                        }
-                        _ => (),
+            .ok_or(ServerError::UnknownSeat(seat_id))
+    }
+    pub fn set_keyboard_focus(
+        &mut self,
+        seat_id: usize,
+        surface: Option<&WlSurface>,
+    ) -> Result<(), ServerError> {
+        if let Some(keyboard) = self.get_keyboard(seat_id)? {
+            #[cfg(feature = "xdg_shell")]
+            let surface = self.popup_manager().keyboard_focus(seat_id, surface);
+            #[cfg(feature = "xdg_shell")]
+            let surface = surface.as_ref();
+            keyboard.set_focus(surface, SERIAL_COUNTER.next_serial());
+        }
+        Ok(())
+    }
+
+    pub fn add_cursor(&mut self, seat_id: usize) -> Result<(), ServerError> {
+        let (seat, _seat_global) = self
+            .seat_globals
+            .get_mut(&seat_id)
+            .ok_or(ServerError::UnknownSeat(seat_id))?;
+        if seat.get_pointer().is_none() {
+            let dispatch_context = self.dispatch_context.clone();
+            let seat_cloned = seat.clone();
+            seat.add_pointer(move |cursor_image_status| {
+                let seat_cloned = &seat_cloned;
+                match &cursor_image_status {
+                    CursorImageStatus::Image(surface) => {
+                        set_surface_kind(surface, SurfaceKind::Cursor);
+                        register_surface(&dispatch_context, surface);
                     }
+                    _ => (),
+                }
 
-                    dispatch_context
-                        .borrow_mut()
-                        .requests
-                        .push(WaylandRequest::Seat {
-                            seat: seat_cloned.clone(),
-                            request: SeatRequest::CursorImage(cursor_image_status),
-                        });
-                });
-            }
+                dispatch_context
+                    .borrow_mut()
+                    .requests
+                    .push(WaylandRequest::Seat {
+                        seat: seat_cloned.clone(),
+                        request: SeatRequest::CursorImage(cursor_image_status),
+                    });
+            });
         }
+        Ok(())
     }
-    pub fn del_cursor(&mut self, seat_id: usize) {
-        if let Some((seat, _seat_global)) = self.seat_globals.get_mut(&seat_id) {
-            seat.remove_pointer();
-        }
+    pub fn del_cursor(&mut self, seat_id: usize) -> Result<(), ServerError> {
+        let (seat, _seat_global) = self
+            .seat_globals
+            .get_mut(&seat_id)
+            .ok_or(ServerError::UnknownSeat(seat_id))?;
+        seat.remove_pointer();
+        Ok(())
     }
-    pub fn get_cursor(&self, seat_id: usize) -> Option<PointerHandle> {
+    pub fn get_cursor(&self, seat_id: usize) -> Result<Option<PointerHandle>, ServerError> {
         self.seat_globals
             .get(&seat_id)
             .map(|seat| seat.0.get_pointer())
-            .flatten()
+            .ok_or(ServerError::UnknownSeat(seat_id))
     }
-    pub fn load_cursor_image(&self) -> WlSurface {
-        unimplemented!()
+    pub fn load_cursor_image(&self) -> Result<WlSurface, ServerError> {
         //CursorTheme::load(24, self.)
+        Err(ServerError::Unimplemented("load_cursor_image"))
     }
 
     pub fn create_output(
@@ -345,19 +1005,340 @@ impl EmbeddedWaylandServer {
         let output = Output::new(&mut self.display, name.into(), physical_properties, None);
         self.output_globals.insert(output_id, output);
     }
-    pub fn destroy_output(&mut self, output_id: usize) {
-        self.output_globals.remove(&output_id);
+    pub fn destroy_output(&mut self, output_id: usize) -> Result<(), ServerError> {
+        self.output_globals
+            .remove(&output_id)
+            .ok_or(ServerError::UnknownOutput(output_id))?;
+        let context = self.dispatch_context.borrow();
+        for (_id, surface) in context.surfaces.iter() {
+            frame::leave_output(surface, output_id);
+        }
+        Ok(())
+    }
+    pub fn get_output(&self, output_id: usize) -> Result<&Output, ServerError> {
+        self.output_globals
+            .get(&output_id)
+            .map(|(output, _global)| output)
+            .ok_or(ServerError::UnknownOutput(output_id))
     }
     pub fn list_outputs(&self) -> impl Iterator<Item = &Output> {
         self.output_globals.values().map(|(output, _global)| output)
     }
+    /// Changes the output state, the toplevels on it get a configure.
+    pub fn change_output(
+        &mut self,
+        output_id: usize,
+        mode: Option<OutputMode>,
+        transform: Option<wl_output::Transform>,
+        scale: Option<i32>,
+        location: Option<Point<i32, Logical>>,
+    ) -> Result<(), ServerError> {
+        self.get_output(output_id)?
+            .change_current_state(mode, transform, scale, location);
+        #[cfg(feature = "xdg_shell")]
+        {
+            let mut context = self.dispatch_context.borrow_mut();
+            let toplevels: Vec<SurfaceKind> = context
+                .surfaces
+                .iter()
+                .filter(|(_id, surface)| frame::outputs(surface).contains(&output_id))
+                .filter_map(|(_id, surface)| {
+                    with_states(surface, |surface_data| surface_kind(surface_data).cloned())
+                        .ok()
+                        .flatten()
+                })
+                .filter(|kind| matches!(kind, SurfaceKind::Toplevel(_)))
+                .collect();
+            for toplevel in toplevels {
+                context
+                    .configures
+                    .schedule(toplevel, ConfigureReason::OutputChange);
+            }
+        }
+        Ok(())
+    }
+
+    /// Marks the surface as visible on the output, its subsurfaces follow it, and sends `wl_surface.enter`.
+    pub fn surface_enter_output(
+        &mut self,
+        surface_id: SurfaceId,
+        output_id: usize,
+    ) -> Result<(), ServerError> {
+        let surface = self
+            .surface(surface_id)
+            .ok_or(ServerError::UnknownSurface(surface_id))?;
+        let output = self.get_output(output_id)?;
+        if frame::enter_output(&surface, output_id) {
+            output.enter(&surface);
+        }
+        Ok(())
+    }
+    pub fn surface_leave_output(
+        &mut self,
+        surface_id: SurfaceId,
+        output_id: usize,
+    ) -> Result<(), ServerError> {
+        let surface = self
+            .surface(surface_id)
+            .ok_or(ServerError::UnknownSurface(surface_id))?;
+        let output = self.get_output(output_id)?;
+        if frame::leave_output(&surface, output_id) {
+            output.leave(&surface);
+        }
+        Ok(())
+    }
+    pub fn surface_outputs(&self, surface_id: SurfaceId) -> Result<Vec<usize>, ServerError> {
+        let surface = self
+            .surface(surface_id)
+            .ok_or(ServerError::UnknownSurface(surface_id))?;
+        Ok(frame::outputs(&surface))
+    }
+
+    /// Fires the frame callbacks of the surfaces visible on the output, `time` is in milliseconds.
+    /// Surfaces on no output are throttled to one frame per hidden frame interval.
+    pub fn send_frame_callbacks(&mut self, output_id: usize, time: u32) -> Result<(), ServerError> {
+        self.get_output(output_id)?;
+        let roots: Vec<WlSurface> = self
+            .dispatch_context
+            .borrow()
+            .surfaces
+            .iter()
+            .filter(|(_id, surface)| get_parent(surface).is_none())
+            .map(|(_id, surface)| surface.clone())
+            .collect();
+        for surface in roots {
+            if frame::frame_due(&surface, output_id, self.hidden_frame_interval) {
+                frame::send_frame_callbacks(&surface, time);
+            }
+        }
+        Ok(())
+    }
+    /// Fires the frame callbacks of the surface and its subsurfaces, whatever their visibility.
+    pub fn send_surface_frame_callbacks(
+        &mut self,
+        surface_id: SurfaceId,
+        time: u32,
+    ) -> Result<(), ServerError> {
+        let surface = self
+            .surface(surface_id)
+            .ok_or(ServerError::UnknownSurface(surface_id))?;
+        frame::send_frame_callbacks(&surface, time);
+        Ok(())
+    }
+    /// Reports the content of the last commit of the surface as shown on the output,
+    /// `time` is measured on the presentation clock and `refresh` is in nanoseconds.
+    #[cfg(feature = "presentation_time")]
+    pub fn presentation_presented(
+        &mut self,
+        surface_id: SurfaceId,
+        output_id: usize,
+        time: Duration,
+        refresh: u32,
+        seq: u64,
+        flags: wp_presentation_feedback::Kind,
+    ) -> Result<(), ServerError> {
+        let surface = self
+            .surface(surface_id)
+            .ok_or(ServerError::UnknownSurface(surface_id))?;
+        let output = self.get_output(output_id)?;
+        presentation::presented(&surface, output, time, refresh, seq, flags)?;
+        Ok(())
+    }
+    #[cfg(feature = "presentation_time")]
+    pub fn presentation_discarded(&mut self, surface_id: SurfaceId) -> Result<(), ServerError> {
+        let surface = self
+            .surface(surface_id)
+            .ok_or(ServerError::UnknownSurface(surface_id))?;
+        presentation::discarded(&surface)?;
+        Ok(())
+    }
+
+    pub fn set_hidden_frame_interval(&mut self, interval: Duration) {
+        self.hidden_frame_interval = interval;
+    }
 
+    /// Lets the crate send the configures, the policy is asked for the size and states
+    /// whenever a toplevel or popup needs one and each surface gets at most one per dispatch.
+    #[cfg(feature = "xdg_shell")]
+    pub fn set_configure_callback(&mut self, policy: impl ConfigurePolicy + 'static) {
+        self.dispatch_context.borrow_mut().configure_policy = Some(Box::new(policy));
+    }
     #[cfg(feature = "xdg_shell")]
-    pub fn set_configure_callback(&mut self) {}
+    pub fn schedule_configure(
+        &mut self,
+        id: SurfaceId,
+        reason: ConfigureReason,
+    ) -> Result<(), ServerError> {
+        let surface = self.surface(id).ok_or(ServerError::UnknownSurface(id))?;
+        let kind = with_states(&surface, |surface_data| surface_kind(surface_data).cloned())?
+            .ok_or(ServerError::UnknownSurface(id))?;
+        self.dispatch_context
+            .borrow_mut()
+            .configures
+            .schedule(kind, reason);
+        Ok(())
+    }
+    #[cfg(feature = "xdg_shell")]
+    pub fn last_acked_serial(&self, id: SurfaceId) -> Result<Option<Serial>, ServerError> {
+        let surface = self.surface(id).ok_or(ServerError::UnknownSurface(id))?;
+        Ok(configure::last_acked_serial(&surface))
+    }
+    #[cfg(feature = "xdg_shell")]
+    pub fn awaiting_configure_ack(&self, id: SurfaceId) -> Result<bool, ServerError> {
+        let surface = self.surface(id).ok_or(ServerError::UnknownSurface(id))?;
+        Ok(configure::awaiting_ack(&surface))
+    }
+    #[cfg(feature = "xdg_shell")]
+    pub fn window(&self, id: SurfaceId) -> Option<Window> {
+        self.dispatch_context.borrow().windows.get(&id).cloned()
+    }
+    /// `Window::schedule_configure` can be called from `f` to send the changes.
+    #[cfg(feature = "xdg_shell")]
+    pub fn with_window<T>(
+        &mut self,
+        id: SurfaceId,
+        f: impl FnOnce(&mut Window) -> T,
+    ) -> Result<T, ServerError> {
+        let mut context = self.dispatch_context.borrow_mut();
+        let window = context
+            .windows
+            .get_mut(&id)
+            .ok_or(ServerError::UnknownSurface(id))?;
+        Ok(f(window))
+    }
+    #[cfg(feature = "xdg_shell")]
+    pub fn list_windows(&self) -> Vec<SurfaceId> {
+        self.dispatch_context
+            .borrow()
+            .windows
+            .keys()
+            .copied()
+            .collect()
+    }
+    /// Interactive move and resize requests start the grabs of the `grabs` module
+    /// unless disabled, the compositor then only applies `WindowMoved`/`WindowResized`.
+    #[cfg(feature = "xdg_shell")]
+    pub fn set_builtin_grabs(&mut self, enabled: bool) {
+        self.dispatch_context.borrow_mut().builtin_grabs = enabled;
+    }
+
+    /// Popup grab chains, keyboard focus changes made through `set_keyboard_focus`
+    /// stay on the topmost grabbing popup. Popups grabbing with a key press or touch
+    /// down serial need the compositor to report these serials to it.
+    #[cfg(feature = "xdg_shell")]
+    pub fn popup_manager(&self) -> PopupManager {
+        self.dispatch_context.borrow().popups.clone()
+    }
+
+    #[cfg(feature = "xdg_shell")]
+    fn send_configures(&mut self) {
+        let (policy, pending) = {
+            let mut context = self.dispatch_context.borrow_mut();
+            (context.configure_policy.take(), context.configures.take())
+        };
+        let mut configured = Vec::new();
+        if let Some(mut policy) = policy {
+            for (surface, reason) in pending {
+                configured.extend(configure::kind_surface(&surface));
+                let decision = policy.configure(&surface, reason);
+                let mut states = ToplevelStateSet::default();
+                for state in &decision.states {
+                    states.set(*state);
+                }
+                let size = decision.size;
+                let marker = configure::apply(&surface, decision);
+                if let SurfaceKind::Toplevel(toplevel) = &surface {
+                    let id = toplevel.get_surface().and_then(registered_id);
+                    let mut context = self.dispatch_context.borrow_mut();
+                    if let Some(window) = id.and_then(|id| context.windows.get_mut(&id)) {
+                        window.set_pending(size, states);
+                        if let Some(marker) = marker {
+                            window.configure_sent(marker);
+                        }
+                    }
+                }
+            }
+            self.dispatch_context.borrow_mut().configure_policy = Some(policy);
+        }
+
+        let mut context = self.dispatch_context.borrow_mut();
+        for window in context.windows.values_mut() {
+            if !window.take_scheduled() {
+                continue;
+            }
+            let surface = window.toplevel().get_surface();
+            if surface.map_or(true, |surface| configured.contains(surface)) {
+                continue;
+            }
+            if let Err(err) = window.send_pending() {
+                log::error!(target: "EWS","Error while sending window configure: {:#?}",err);
+            }
+        }
+    }
 }
 
-impl std::os::unix::io::AsRawFd for EmbeddedWaylandServer {
-    fn as_raw_fd(&self) -> std::os::unix::io::RawFd {
-        self.display.get_poll_fd()
+impl AsRawFd for EmbeddedWaylandServer {
+    fn as_raw_fd(&self) -> RawFd {
+        match &self.poll_fd {
+            Some(poll_fd) => poll_fd.as_raw_fd(),
+            None => self.display.get_poll_fd(),
+        }
+    }
+}
+
+impl calloop::EventSource for EmbeddedWaylandServer {
+    type Event = WaylandRequest;
+    type Metadata = EmbeddedWaylandServer;
+    type Ret = ();
+
+    fn process_events<F>(
+        &mut self,
+        _readiness: calloop::Readiness,
+        _token: calloop::Token,
+        mut callback: F,
+    ) -> std::io::Result<calloop::PostAction>
+    where
+        F: FnMut(Self::Event, &mut Self::Metadata) -> Self::Ret,
+    {
+        let requests = self.dispatch_pending().map_err(|err| match err {
+            ServerError::Dispatch(err) => err,
+            err => std::io::Error::new(std::io::ErrorKind::Other, err.to_string()),
+        })?;
+        for request in requests {
+            callback(request, self);
+        }
+        // The loop goes back to sleep, the events sent by the callback must not wait.
+        self.flush_clients();
+        Ok(calloop::PostAction::Continue)
+    }
+
+    fn register(
+        &mut self,
+        poll: &mut calloop::Poll,
+        token_factory: &mut calloop::TokenFactory,
+    ) -> std::io::Result<()> {
+        poll.register(
+            self.as_raw_fd(),
+            calloop::Interest::READ,
+            calloop::Mode::Level,
+            token_factory.token(),
+        )
+    }
+
+    fn reregister(
+        &mut self,
+        poll: &mut calloop::Poll,
+        token_factory: &mut calloop::TokenFactory,
+    ) -> std::io::Result<()> {
+        poll.reregister(
+            self.as_raw_fd(),
+            calloop::Interest::READ,
+            calloop::Mode::Level,
+            token_factory.token(),
+        )
+    }
+
+    fn unregister(&mut self, poll: &mut calloop::Poll) -> std::io::Result<()> {
+        poll.unregister(self.as_raw_fd())
     }
 }
@@ -0,0 +1,68 @@
+use crate::definitions::*;
+
+pub(crate) fn bytes_per_pixel(format: ShmFormat) -> Option<usize> {
+    match format {
+        ShmFormat::Argb8888 | ShmFormat::Xrgb8888 | ShmFormat::Abgr8888 | ShmFormat::Xbgr8888 => {
+            Some(4)
+        }
+        ShmFormat::Rgb565 => Some(2),
+        _ => None,
+    }
+}
+
+/// Shm formats are little endian and alpha premultiplied, so is the result.
+pub(crate) fn premultiplied_rgba(format: ShmFormat, pixel: &[u8]) -> [u8; 4] {
+    match format {
+        ShmFormat::Argb8888 => [pixel[2], pixel[1], pixel[0], pixel[3]],
+        ShmFormat::Xrgb8888 => [pixel[2], pixel[1], pixel[0], 255],
+        ShmFormat::Abgr8888 => [pixel[0], pixel[1], pixel[2], pixel[3]],
+        ShmFormat::Xbgr8888 => [pixel[0], pixel[1], pixel[2], 255],
+        ShmFormat::Rgb565 => {
+            let value = u16::from_le_bytes([pixel[0], pixel[1]]);
+            let red = ((value >> 11) & 0x1f) as u8;
+            let green = ((value >> 5) & 0x3f) as u8;
+            let blue = (value & 0x1f) as u8;
+            [
+                (red << 3) | (red >> 2),
+                (green << 2) | (green >> 4),
+                (blue << 3) | (blue >> 2),
+                255,
+            ]
+        }
+        _ => [0, 0, 0, 0],
+    }
+}
+
+pub(crate) fn unpremultiply([red, green, blue, alpha]: [u8; 4]) -> [u8; 4] {
+    match alpha {
+        0 => [0, 0, 0, 0],
+        255 => [red, green, blue, alpha],
+        _ => {
+            let straight =
+                |value: u8| ((value as u32 * 255 + alpha as u32 / 2) / alpha as u32).min(255) as u8;
+            [straight(red), straight(green), straight(blue), alpha]
+        }
+    }
+}
+
+#[cfg(feature = "software_renderer")]
+pub(crate) fn premultiply([red, green, blue, alpha]: [u8; 4]) -> [u8; 4] {
+    let premultiplied = |value: u8| ((value as u32 * alpha as u32 + 127) / 255) as u8;
+    [
+        premultiplied(red),
+        premultiplied(green),
+        premultiplied(blue),
+        alpha,
+    ]
+}
+
+/// Composites a premultiplied pixel over another.
+#[cfg(feature = "software_renderer")]
+pub(crate) fn blend(source: [u8; 4], destination: [u8; 4]) -> [u8; 4] {
+    let remaining = 255 - source[3] as u32;
+    let mut result = [0; 4];
+    for ((result, source), destination) in result.iter_mut().zip(&source).zip(&destination) {
+        *result = (*source as u32 + (*destination as u32 * remaining + 127) / 255).min(255) as u8;
+    }
+    result
+}
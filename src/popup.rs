@@ -0,0 +1,343 @@
+use crate::definitions::*;
+use crate::surface::registered_id;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/// Popups of a grab chain from the bottom to the top, with their surface ids.
+#[derive(Debug)]
+pub(crate) struct PopupStack<T> {
+    popups: Vec<(Option<SurfaceId>, T)>,
+}
+impl<T> Default for PopupStack<T> {
+    fn default() -> Self {
+        Self { popups: Vec::new() }
+    }
+}
+impl<T> PopupStack<T> {
+    pub fn push(&mut self, id: Option<SurfaceId>, popup: T) {
+        self.popups.push((id, popup));
+    }
+    pub fn top(&self) -> Option<&T> {
+        self.popups.last().map(|(_id, popup)| popup)
+    }
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.popups.iter().map(|(_id, popup)| popup)
+    }
+    pub fn is_empty(&self) -> bool {
+        self.popups.is_empty()
+    }
+    /// Removes every popup, topmost first.
+    pub fn take_all(&mut self) -> Vec<T> {
+        self.popups
+            .drain(..)
+            .rev()
+            .map(|(_id, popup)| popup)
+            .collect()
+    }
+    /// Removes the destroyed popup and the ones above it, `None` if it is not part of
+    /// the stack. The popups above are returned topmost first, to be dismissed.
+    pub fn destroyed(&mut self, id: SurfaceId) -> Option<Vec<T>> {
+        let index = self
+            .popups
+            .iter()
+            .position(|(popup_id, _popup)| *popup_id == Some(id))?;
+        let mut above = self.popups.split_off(index);
+        above.remove(0);
+        Some(above.into_iter().rev().map(|(_id, popup)| popup).collect())
+    }
+}
+
+/// Serials of the last user events of a seat that Smithay does not keep track of:
+/// key presses, touch downs and the button presses made during a popup grab, which
+/// are not part of an implicit pointer grab.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct InputSerials {
+    pub keyboard: Option<Serial>,
+    pub touch_down: Option<Serial>,
+    pub popup_button: Option<Serial>,
+}
+impl InputSerials {
+    /// A popup grab must follow a button press, a key press or a touch down.
+    pub fn accepts(&self, serial: Serial, pointer_grab: bool) -> bool {
+        pointer_grab
+            || self.keyboard == Some(serial)
+            || self.touch_down == Some(serial)
+            || self.popup_button == Some(serial)
+    }
+}
+
+/// Popups holding an explicit grab on one seat.
+#[derive(Debug)]
+struct PopupGrabChain {
+    seat: Seat,
+    root: WlSurface,
+    root_id: Option<SurfaceId>,
+    popups: PopupStack<PopupSurface>,
+}
+impl PopupGrabChain {
+    fn top_surface(&self) -> Option<WlSurface> {
+        match self.popups.top() {
+            Some(popup) => popup.get_surface().cloned(),
+            None => Some(self.root.clone()).filter(|root| root.as_ref().is_alive()),
+        }
+    }
+    fn owns(&self, surface: &WlSurface) -> bool {
+        self.root.as_ref().same_client_as(surface.as_ref())
+    }
+}
+
+/// Sends `popup_done` to popups ordered from the topmost down, as the protocol requires.
+fn dismiss(popups: Vec<PopupSurface>) {
+    for popup in popups {
+        if popup.alive() {
+            popup.send_popup_done();
+        }
+    }
+}
+
+fn set_keyboard_focus(seat: &Seat, focus: Option<WlSurface>) {
+    if let Some(keyboard) = seat.get_keyboard() {
+        keyboard.set_focus(focus.as_ref(), SERIAL_COUNTER.next_serial());
+    }
+}
+
+fn id_of_seat(seat: &Seat) -> Option<usize> {
+    seat.user_data().get::<SeatId>().map(|id| id.0)
+}
+
+/// Tracks the popup grab chains of every seat. While a chain is active the keyboard
+/// focus stays on its topmost popup and the pointer only reaches surfaces of the
+/// grabbing client, a click anywhere else dismisses the whole chain.
+#[derive(Debug, Clone, Default)]
+pub struct PopupManager {
+    chains: Rc<RefCell<HashMap<usize, PopupGrabChain>>>,
+    input_serials: Rc<RefCell<HashMap<usize, InputSerials>>>,
+}
+impl PopupManager {
+    /// Records the serial of a key press sent to the seat keyboard, popups can then
+    /// grab the seat with it.
+    pub fn set_keyboard_serial(&self, seat_id: usize, serial: Serial) {
+        self.input_serials
+            .borrow_mut()
+            .entry(seat_id)
+            .or_default()
+            .keyboard = Some(serial);
+    }
+    /// Records the serial of a touch down event sent to the seat.
+    pub fn set_touch_down_serial(&self, seat_id: usize, serial: Serial) {
+        self.input_serials
+            .borrow_mut()
+            .entry(seat_id)
+            .or_default()
+            .touch_down = Some(serial);
+    }
+
+    pub fn is_grabbed(&self, seat_id: usize) -> bool {
+        self.chains.borrow().contains_key(&seat_id)
+    }
+    /// The grabbing popups of the seat, from the bottom to the top.
+    pub fn grabbed_popups(&self, seat_id: usize) -> Vec<PopupSurface> {
+        self.chains
+            .borrow()
+            .get(&seat_id)
+            .map(|chain| chain.popups.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+    pub fn top_popup(&self, seat_id: usize) -> Option<PopupSurface> {
+        self.chains
+            .borrow()
+            .get(&seat_id)
+            .and_then(|chain| chain.popups.top().cloned())
+    }
+    /// The surface that should get the keyboard focus instead of `requested`.
+    pub fn keyboard_focus(
+        &self,
+        seat_id: usize,
+        requested: Option<&WlSurface>,
+    ) -> Option<WlSurface> {
+        match self.chains.borrow().get(&seat_id) {
+            Some(chain) => chain.top_surface(),
+            None => requested.cloned(),
+        }
+    }
+
+    /// Dismisses every popup of the seat chain, the keyboard focus goes back to its root.
+    pub fn dismiss_all(&self, seat_id: usize) {
+        let chain = self.chains.borrow_mut().remove(&seat_id);
+        if let Some(mut chain) = chain {
+            dismiss(chain.popups.take_all());
+            if chain.root.as_ref().is_alive() {
+                set_keyboard_focus(&chain.seat, Some(chain.root));
+            }
+        }
+    }
+
+    /// Handles `xdg_popup.grab`, a denied grab dismisses the popup right away.
+    pub(crate) fn grab(&self, popup: &PopupSurface, seat: &WlSeat, serial: Serial) {
+        let (seat, seat_id) = match Seat::from_resource(seat).and_then(|seat| {
+            let seat_id = id_of_seat(&seat)?;
+            Some((seat, seat_id))
+        }) {
+            Some(seat) => seat,
+            None => return popup.send_popup_done(),
+        };
+        let (surface, parent) = match (popup.get_surface(), popup.get_parent_surface()) {
+            (Some(surface), Some(parent)) => (surface.clone(), parent),
+            _ => return popup.send_popup_done(),
+        };
+        let pointer = seat.get_pointer();
+
+        let mut chains = self.chains.borrow_mut();
+        // A nested popup must be opened from the topmost one, a new menu of another
+        // client or window replaces the current chain.
+        let nested = match chains.get(&seat_id) {
+            Some(chain) if chain.top_surface().as_ref() == Some(&parent) => true,
+            Some(chain) if chain.owns(&parent) => {
+                log::debug!(target: "EWS","Denied popup grab: the parent is not the topmost popup");
+                return popup.send_popup_done();
+            }
+            _ => false,
+        };
+        let pointer_grab = pointer
+            .as_ref()
+            .map(|pointer| pointer.has_grab(serial))
+            .unwrap_or(false);
+        let input_serials = self
+            .input_serials
+            .borrow()
+            .get(&seat_id)
+            .copied()
+            .unwrap_or_default();
+        if !input_serials.accepts(serial, pointer_grab) {
+            log::debug!(target: "EWS","Denied popup grab: serial {:?} does not match a button press, key press or touch down",serial);
+            return popup.send_popup_done();
+        }
+        if !nested {
+            if let Some(mut previous) = chains.remove(&seat_id) {
+                dismiss(previous.popups.take_all());
+            }
+            chains.insert(
+                seat_id,
+                PopupGrabChain {
+                    seat: seat.clone(),
+                    root_id: registered_id(&parent),
+                    root: parent,
+                    popups: PopupStack::default(),
+                },
+            );
+        }
+        if let Some(chain) = chains.get_mut(&seat_id) {
+            chain.popups.push(registered_id(&surface), popup.clone());
+        }
+        drop(chains);
+
+        set_keyboard_focus(&seat, Some(surface.clone()));
+        if let Some(pointer) = pointer {
+            let start_data = pointer.grab_start_data().unwrap_or(GrabStartData {
+                focus: None,
+                button: 0,
+                location: (0.0, 0.0).into(),
+            });
+            let focus = start_data
+                .focus
+                .clone()
+                .filter(|(focus, _location)| focus.as_ref().same_client_as(surface.as_ref()));
+            pointer.set_grab(
+                PopupPointerGrab {
+                    start_data,
+                    manager: self.clone(),
+                    seat_id,
+                    surface,
+                    focus,
+                },
+                serial,
+            );
+        }
+    }
+
+    /// Popups above a destroyed surface of a chain are dismissed.
+    pub(crate) fn surface_destroyed(&self, id: SurfaceId) {
+        let mut focus_changes = Vec::new();
+        let mut dismissed = Vec::new();
+        {
+            let mut chains = self.chains.borrow_mut();
+            for chain in chains.values_mut() {
+                if chain.root_id == Some(id) {
+                    dismissed.push(chain.popups.take_all());
+                } else if let Some(above) = chain.popups.destroyed(id) {
+                    dismissed.push(above);
+                    focus_changes.push((chain.seat.clone(), chain.top_surface()));
+                }
+            }
+            chains.retain(|_seat_id, chain| !chain.popups.is_empty());
+        }
+        for popups in dismissed {
+            dismiss(popups);
+        }
+        for (seat, focus) in focus_changes {
+            set_keyboard_focus(&seat, focus);
+        }
+    }
+}
+
+/// Keeps the pointer on the grabbing client, until the chain is dismissed.
+struct PopupPointerGrab {
+    start_data: GrabStartData,
+    manager: PopupManager,
+    seat_id: usize,
+    /// Any surface of the grabbing client.
+    surface: WlSurface,
+    focus: Option<(WlSurface, Point<i32, Logical>)>,
+}
+impl PointerGrab for PopupPointerGrab {
+    fn motion(
+        &mut self,
+        handle: &mut PointerInnerHandle<'_>,
+        location: Point<f64, Logical>,
+        focus: Option<(WlSurface, Point<i32, Logical>)>,
+        serial: Serial,
+        time: u32,
+    ) {
+        if !self.manager.is_grabbed(self.seat_id) {
+            handle.unset_grab(serial, time);
+            handle.motion(location, focus, serial, time);
+            return;
+        }
+        self.focus = focus
+            .filter(|(surface, _location)| surface.as_ref().same_client_as(self.surface.as_ref()));
+        handle.motion(location, self.focus.clone(), serial, time);
+    }
+    fn button(
+        &mut self,
+        handle: &mut PointerInnerHandle<'_>,
+        button: u32,
+        state: ButtonState,
+        serial: Serial,
+        time: u32,
+    ) {
+        if !self.manager.is_grabbed(self.seat_id) {
+            handle.unset_grab(serial, time);
+        } else if state == ButtonState::Pressed && self.focus.is_none() {
+            // Clicking outside of the client closes the menus, the click is not forwarded.
+            self.manager.dismiss_all(self.seat_id);
+            handle.unset_grab(serial, time);
+            return;
+        } else if state == ButtonState::Pressed {
+            // Nested popups are opened with the serial of a press inside the chain.
+            self.manager
+                .input_serials
+                .borrow_mut()
+                .entry(self.seat_id)
+                .or_default()
+                .popup_button = Some(serial);
+        }
+        handle.button(button, state, serial, time);
+    }
+    fn axis(&mut self, handle: &mut PointerInnerHandle<'_>, details: AxisFrame) {
+        handle.axis(details)
+    }
+    fn start_data(&self) -> &GrabStartData {
+        &self.start_data
+    }
+}
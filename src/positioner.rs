@@ -0,0 +1,166 @@
+use crate::definitions::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Side {
+    Start,
+    Center,
+    End,
+}
+impl Side {
+    fn flipped(self) -> Self {
+        match self {
+            Side::Start => Side::End,
+            Side::Center => Side::Center,
+            Side::End => Side::Start,
+        }
+    }
+}
+
+fn anchor_sides(anchor: Anchor) -> (Side, Side) {
+    match anchor {
+        Anchor::Top => (Side::Center, Side::Start),
+        Anchor::Bottom => (Side::Center, Side::End),
+        Anchor::Left => (Side::Start, Side::Center),
+        Anchor::Right => (Side::End, Side::Center),
+        Anchor::TopLeft => (Side::Start, Side::Start),
+        Anchor::BottomLeft => (Side::Start, Side::End),
+        Anchor::TopRight => (Side::End, Side::Start),
+        Anchor::BottomRight => (Side::End, Side::End),
+        _ => (Side::Center, Side::Center),
+    }
+}
+
+fn gravity_sides(gravity: Gravity) -> (Side, Side) {
+    match gravity {
+        Gravity::Top => (Side::Center, Side::Start),
+        Gravity::Bottom => (Side::Center, Side::End),
+        Gravity::Left => (Side::Start, Side::Center),
+        Gravity::Right => (Side::End, Side::Center),
+        Gravity::TopLeft => (Side::Start, Side::Start),
+        Gravity::BottomLeft => (Side::Start, Side::End),
+        Gravity::TopRight => (Side::End, Side::Start),
+        Gravity::BottomRight => (Side::End, Side::End),
+        _ => (Side::Center, Side::Center),
+    }
+}
+
+/// The positioner rules along one axis.
+#[derive(Debug, Clone, Copy)]
+struct AxisRules {
+    anchor_start: i32,
+    anchor_length: i32,
+    anchor: Side,
+    gravity: Side,
+    offset: i32,
+    length: i32,
+}
+impl AxisRules {
+    fn position(&self) -> i32 {
+        let anchor = match self.anchor {
+            Side::Start => self.anchor_start,
+            Side::Center => self.anchor_start + self.anchor_length / 2,
+            Side::End => self.anchor_start + self.anchor_length,
+        } + self.offset;
+        match self.gravity {
+            Side::Start => anchor - self.length,
+            Side::Center => anchor - self.length / 2,
+            Side::End => anchor,
+        }
+    }
+
+    /// Flipping mirrors the anchor, the gravity and the offset.
+    fn flipped(&self) -> Self {
+        Self {
+            anchor: self.anchor.flipped(),
+            gravity: self.gravity.flipped(),
+            offset: -self.offset,
+            ..*self
+        }
+    }
+
+    /// Applies the adjustments in the order of the specification: flip, then slide,
+    /// then resize, each one only if the popup is still constrained.
+    fn solve(&self, min: i32, max: i32, flip: bool, slide: bool, resize: bool) -> (i32, i32) {
+        let constrained = |start: i32, length: i32| start < min || start + length > max;
+        let mut start = self.position();
+        let mut length = self.length;
+        if !constrained(start, length) {
+            return (start, length);
+        }
+
+        if flip {
+            let flipped = self.flipped().position();
+            if !constrained(flipped, length) {
+                return (flipped, length);
+            }
+        }
+
+        if slide {
+            let before = min - start;
+            let after = start + length - max;
+            if before > 0 {
+                start += before;
+            } else if after > 0 {
+                // Never slides past the start of the constraint, it takes priority.
+                start -= after.min(-before);
+            }
+        }
+
+        if resize && constrained(start, length) {
+            let (resized_start, resized_end) = (start.max(min), (start + length).min(max));
+            if resized_end > resized_start {
+                start = resized_start;
+                length = resized_end - resized_start;
+            }
+        }
+        (start, length)
+    }
+}
+
+/// Places a popup following its positioner, `parent` is the window geometry of the
+/// parent surface and `constraint` the area the popup must fit in (usually the usable
+/// area of the output), both in the same coordinate space. The result is relative to
+/// the parent, ready for `ConfigureDecision::geometry`.
+pub fn solve_positioner(
+    positioner: &PositionerState,
+    parent: Rectangle<i32, Logical>,
+    constraint: Rectangle<i32, Logical>,
+) -> Rectangle<i32, Logical> {
+    let (anchor_x, anchor_y) = anchor_sides(positioner.anchor_edges);
+    let (gravity_x, gravity_y) = gravity_sides(positioner.gravity);
+    let adjustment = positioner.constraint_adjustment;
+    let anchor_rect = positioner.anchor_rect;
+    let constraint_start = constraint.loc - parent.loc;
+
+    let (x, width) = AxisRules {
+        anchor_start: anchor_rect.loc.x,
+        anchor_length: anchor_rect.size.w,
+        anchor: anchor_x,
+        gravity: gravity_x,
+        offset: positioner.offset.x,
+        length: positioner.rect_size.w,
+    }
+    .solve(
+        constraint_start.x,
+        constraint_start.x + constraint.size.w,
+        adjustment.contains(ConstraintAdjustment::FlipX),
+        adjustment.contains(ConstraintAdjustment::SlideX),
+        adjustment.contains(ConstraintAdjustment::ResizeX),
+    );
+    let (y, height) = AxisRules {
+        anchor_start: anchor_rect.loc.y,
+        anchor_length: anchor_rect.size.h,
+        anchor: anchor_y,
+        gravity: gravity_y,
+        offset: positioner.offset.y,
+        length: positioner.rect_size.h,
+    }
+    .solve(
+        constraint_start.y,
+        constraint_start.y + constraint.size.h,
+        adjustment.contains(ConstraintAdjustment::FlipY),
+        adjustment.contains(ConstraintAdjustment::SlideY),
+        adjustment.contains(ConstraintAdjustment::ResizeY),
+    );
+    Rectangle::from_loc_and_size((x, y), (width, height))
+}
@@ -0,0 +1,101 @@
+use crate::definitions::*;
+use crate::filter::GlobalPolicy;
+use std::time::Duration;
+
+#[derive(Default)]
+struct PresentationFeedbackCachedState {
+    callbacks: Vec<WpPresentationFeedback>,
+}
+impl Cacheable for PresentationFeedbackCachedState {
+    fn commit(&mut self) -> Self {
+        Self {
+            callbacks: std::mem::take(&mut self.callbacks),
+        }
+    }
+    fn merge_into(self, into: &mut Self) {
+        // Feedback not reported before the next content update is superseded.
+        for callback in into.callbacks.drain(..) {
+            callback.discarded();
+        }
+        into.callbacks = self.callbacks;
+    }
+}
+
+pub(crate) fn init_presentation_global(
+    display: &mut Display,
+    clock_id: u32,
+    global_policy: &GlobalPolicy,
+) -> Global<WpPresentation> {
+    display.create_global_with_filter::<WpPresentation, _, _>(
+        1,
+        Filter::new(
+            move |(presentation, _version): (Main<WpPresentation>, u32), _, _| {
+                presentation.quick_assign(|_presentation, request, _| {
+                    if let wp_presentation::Request::Feedback { surface, callback } = request {
+                        let callback = (*callback).clone();
+                        if let Err(err) = with_states(&surface, |surface_data| {
+                            surface_data
+                                .cached_state
+                                .pending::<PresentationFeedbackCachedState>()
+                                .callbacks
+                                .push(callback);
+                        }) {
+                            log::error!(target: "EWS","Error while queuing presentation feedback: {:#?}",err);
+                        }
+                    }
+                });
+                presentation.clock_id(clock_id);
+            },
+        ),
+        global_policy.client_filter(WpPresentation::NAME),
+    )
+}
+
+fn take_feedback(surface: &WlSurface) -> Result<Vec<WpPresentationFeedback>, DeadResource> {
+    with_states(surface, |surface_data| {
+        std::mem::take(
+            &mut surface_data
+                .cached_state
+                .current::<PresentationFeedbackCachedState>()
+                .callbacks,
+        )
+    })
+}
+
+/// `time` is measured on the clock advertised by the global, `refresh` is in nanoseconds.
+pub(crate) fn presented(
+    surface: &WlSurface,
+    output: &Output,
+    time: Duration,
+    refresh: u32,
+    seq: u64,
+    flags: wp_presentation_feedback::Kind,
+) -> Result<(), DeadResource> {
+    let callbacks = take_feedback(surface)?;
+    if callbacks.is_empty() {
+        return Ok(());
+    }
+    let seconds = time.as_secs();
+    for callback in callbacks {
+        if let Some(client) = callback.as_ref().client() {
+            output.with_client_outputs(client, |wl_output| callback.sync_output(wl_output));
+        }
+        callback.presented(
+            (seconds >> 32) as u32,
+            seconds as u32,
+            time.subsec_nanos(),
+            refresh,
+            (seq >> 32) as u32,
+            seq as u32,
+            flags,
+        );
+    }
+    Ok(())
+}
+
+pub(crate) fn discarded(surface: &WlSurface) -> Result<(), DeadResource> {
+    for callback in take_feedback(surface)? {
+        callback.discarded();
+    }
+    Ok(())
+}
@@ -0,0 +1,137 @@
+use std::ffi::{OsStr, OsString};
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+
+#[derive(Debug)]
+pub(crate) struct ListeningSocket {
+    listener: UnixListener,
+    name: Option<OsString>,
+    owned_paths: Option<(PathBuf, PathBuf, File)>,
+}
+impl ListeningSocket {
+    pub fn bind(name: impl AsRef<OsStr>) -> io::Result<Self> {
+        let runtime_dir = std::env::var_os("XDG_RUNTIME_DIR")
+            .map(PathBuf::from)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "XDG_RUNTIME_DIR is not set"))?;
+        let name = name.as_ref().to_os_string();
+        let socket_path = runtime_dir.join(&name);
+        let mut lock_name = name.clone();
+        lock_name.push(".lock");
+        let lock_path = runtime_dir.join(lock_name);
+
+        let lock = std::fs::OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .mode(0o660)
+            .open(&lock_path)?;
+        if unsafe { libc::flock(lock.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("{:?} is used by another compositor", socket_path),
+            ));
+        }
+        // Holding the lock means any socket file left there is stale.
+        let _ = std::fs::remove_file(&socket_path);
+
+        let listener = UnixListener::bind(&socket_path)?;
+        listener.set_nonblocking(true)?;
+        Ok(Self {
+            listener,
+            name: Some(name),
+            owned_paths: Some((socket_path, lock_path, lock)),
+        })
+    }
+
+    pub fn bind_auto() -> io::Result<Self> {
+        for index in 0..33 {
+            match Self::bind(format!("wayland-{}", index)) {
+                Ok(socket) => return Ok(socket),
+                Err(err) if err.kind() == io::ErrorKind::AddrInUse => continue,
+                Err(err) => return Err(err),
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            "No wayland socket name available",
+        ))
+    }
+
+    pub fn from_listener(listener: UnixListener) -> io::Result<Self> {
+        listener.set_nonblocking(true)?;
+        let name = listener.local_addr().ok().and_then(|address| {
+            address
+                .as_pathname()
+                .and_then(|path| path.file_name())
+                .map(OsStr::to_os_string)
+        });
+        Ok(Self {
+            listener,
+            name,
+            owned_paths: None,
+        })
+    }
+
+    pub fn name(&self) -> Option<&OsStr> {
+        self.name.as_deref()
+    }
+
+    pub fn accept(&self) -> io::Result<Option<UnixStream>> {
+        match self.listener.accept() {
+            Ok((stream, _address)) => Ok(Some(stream)),
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+}
+impl AsRawFd for ListeningSocket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.listener.as_raw_fd()
+    }
+}
+impl Drop for ListeningSocket {
+    fn drop(&mut self) {
+        if let Some((socket_path, lock_path, _lock)) = &self.owned_paths {
+            let _ = std::fs::remove_file(socket_path);
+            let _ = std::fs::remove_file(lock_path);
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct PollFd(RawFd);
+impl PollFd {
+    pub fn new(fds: &[RawFd]) -> io::Result<Self> {
+        let epoll = unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) };
+        if epoll < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let poll_fd = Self(epoll);
+        for fd in fds {
+            let mut event = libc::epoll_event {
+                events: libc::EPOLLIN as u32,
+                u64: *fd as u64,
+            };
+            if unsafe { libc::epoll_ctl(epoll, libc::EPOLL_CTL_ADD, *fd, &mut event) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(poll_fd)
+    }
+}
+impl AsRawFd for PollFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0
+    }
+}
+impl Drop for PollFd {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.0);
+        }
+    }
+}
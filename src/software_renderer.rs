@@ -0,0 +1,269 @@
+use crate::buffer::current_buffer;
+use crate::capture::RgbaImage;
+use crate::definitions::*;
+use crate::pixel::{blend, bytes_per_pixel, premultiplied_rgba, premultiply, unpremultiply};
+use crate::surface::subsurface_offset;
+
+struct DrawItem {
+    buffer: AttachedBuffer,
+    location: Point<i32, Logical>,
+    buffer_scale: i32,
+    transform: wl_output::Transform,
+}
+impl DrawItem {
+    fn logical_size(&self) -> Option<Size<i32, Logical>> {
+        Some(logical_size(
+            self.buffer.size()?,
+            self.buffer_scale,
+            self.transform,
+        ))
+    }
+}
+
+fn logical_size(
+    buffer_size: Size<i32, Physical>,
+    buffer_scale: i32,
+    transform: wl_output::Transform,
+) -> Size<i32, Logical> {
+    use wl_output::Transform;
+    let buffer_scale = buffer_scale.max(1);
+    match transform {
+        Transform::_90 | Transform::_270 | Transform::Flipped90 | Transform::Flipped270 => {
+            (buffer_size.h / buffer_scale, buffer_size.w / buffer_scale).into()
+        }
+        _ => (buffer_size.w / buffer_scale, buffer_size.h / buffer_scale).into(),
+    }
+}
+
+/// Pixels of a buffer, detached from the surface that holds it.
+pub(crate) enum PixelSource<'a> {
+    Solid([u8; 4]),
+    Shm {
+        data: &'a [u8],
+        format: ShmFormat,
+        stride: usize,
+    },
+}
+
+/// Premultiplied pixels of the output being composited.
+pub(crate) struct Canvas {
+    pixels: Vec<[u8; 4]>,
+    width: u32,
+    height: u32,
+    scale: i32,
+}
+impl Canvas {
+    pub fn new(size: Size<i32, Logical>, scale: i32, background: [u8; 4]) -> Self {
+        let scale = scale.max(1);
+        let width = (size.w * scale).max(0) as u32;
+        let height = (size.h * scale).max(0) as u32;
+        Self {
+            pixels: vec![premultiply(background); (width * height) as usize],
+            width,
+            height,
+            scale,
+        }
+    }
+
+    /// Draws a buffer of `buffer_size` pixels at the logical `location`.
+    pub fn draw(
+        &mut self,
+        source: &PixelSource<'_>,
+        buffer_size: Size<i32, Physical>,
+        location: Point<i32, Logical>,
+        buffer_scale: i32,
+        transform: wl_output::Transform,
+    ) {
+        use wl_output::Transform;
+        if buffer_size.w <= 0 || buffer_size.h <= 0 {
+            return;
+        }
+        let logical_size = logical_size(buffer_size, buffer_scale, transform);
+        let buffer_scale = buffer_scale.max(1);
+        let (logical_width, logical_height) = (logical_size.w, logical_size.h);
+        let (lw, lh) = (logical_width as f64, logical_height as f64);
+        let left = location.x * self.scale;
+        let top = location.y * self.scale;
+        let right = (left + logical_width * self.scale).min(self.width as i32);
+        let bottom = (top + logical_height * self.scale).min(self.height as i32);
+
+        // Maps a surface-local logical position to buffer pixels.
+        let to_buffer = |sx: f64, sy: f64| {
+            let (bx, by) = match transform {
+                Transform::_90 => (sy, lw - sx),
+                Transform::_180 => (lw - sx, lh - sy),
+                Transform::_270 => (lh - sy, sx),
+                Transform::Flipped => (lw - sx, sy),
+                Transform::Flipped90 => (sy, sx),
+                Transform::Flipped180 => (sx, lh - sy),
+                Transform::Flipped270 => (lh - sy, lw - sx),
+                _ => (sx, sy),
+            };
+            let bx = ((bx * buffer_scale as f64) as i32).clamp(0, buffer_size.w - 1);
+            let by = ((by * buffer_scale as f64) as i32).clamp(0, buffer_size.h - 1);
+            (bx as usize, by as usize)
+        };
+
+        let (width, scale) = (self.width, self.scale as f64);
+        let pixels = &mut self.pixels;
+        let mut draw_pixel = |x: i32, y: i32, source: [u8; 4]| {
+            let index = (y as u32 * width + x as u32) as usize;
+            pixels[index] = blend(source, pixels[index]);
+        };
+        match source {
+            PixelSource::Solid(rgba) => {
+                for y in top.max(0)..bottom {
+                    for x in left.max(0)..right {
+                        draw_pixel(x, y, *rgba);
+                    }
+                }
+            }
+            PixelSource::Shm {
+                data,
+                format,
+                stride,
+            } => {
+                let bpp = match bytes_per_pixel(*format) {
+                    Some(bpp) => bpp,
+                    None => {
+                        log::debug!(target: "EWS","SoftwareRenderer: unsupported shm format {:?}",format);
+                        return;
+                    }
+                };
+                for y in top.max(0)..bottom {
+                    for x in left.max(0)..right {
+                        let sx = (x - left) as f64 / scale + 0.5 / scale;
+                        let sy = (y - top) as f64 / scale + 0.5 / scale;
+                        let (bx, by) = to_buffer(sx, sy);
+                        let offset = by * stride + bx * bpp;
+                        if let Some(pixel) = data.get(offset..offset + bpp) {
+                            draw_pixel(x, y, premultiplied_rgba(*format, pixel));
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn draw_item(&mut self, item: &DrawItem) {
+        let buffer_size = match item.buffer.size() {
+            Some(buffer_size) => buffer_size,
+            None => return,
+        };
+        match &item.buffer {
+            AttachedBuffer::SinglePixel { rgba, .. } => self.draw(
+                &PixelSource::Solid(*rgba),
+                buffer_size,
+                item.location,
+                item.buffer_scale,
+                item.transform,
+            ),
+            AttachedBuffer::Shm { format, stride, .. } => {
+                item.buffer.with_shm_data(|data| {
+                    let source = PixelSource::Shm {
+                        data,
+                        format: *format,
+                        stride: *stride as usize,
+                    };
+                    self.draw(
+                        &source,
+                        buffer_size,
+                        item.location,
+                        item.buffer_scale,
+                        item.transform,
+                    )
+                });
+            }
+            _ => (),
+        }
+    }
+
+    pub fn into_image(self) -> RgbaImage {
+        let mut image = RgbaImage::new(self.width, self.height);
+        for (target, pixel) in image.data.chunks_exact_mut(4).zip(self.pixels) {
+            target.copy_from_slice(&unpremultiply(pixel));
+        }
+        image
+    }
+}
+
+/// Composites shm surfaces into an `RgbaImage` on the CPU, dmabuf buffers are skipped.
+#[derive(Debug, Clone)]
+pub struct SoftwareRenderer {
+    pub scale: i32,
+    pub background: [u8; 4],
+}
+impl Default for SoftwareRenderer {
+    fn default() -> Self {
+        Self {
+            scale: 1,
+            background: [0, 0, 0, 0],
+        }
+    }
+}
+impl SoftwareRenderer {
+    pub fn new(scale: i32) -> Self {
+        Self {
+            scale: scale.max(1),
+            ..Self::default()
+        }
+    }
+
+    /// `surfaces` are root surfaces from bottom to top with their location in the
+    /// output, their subsurfaces are drawn along with them.
+    pub fn render(
+        &self,
+        size: Size<i32, Logical>,
+        surfaces: impl IntoIterator<Item = (WlSurface, Point<i32, Logical>)>,
+    ) -> RgbaImage {
+        let mut canvas = Canvas::new(size, self.scale, self.background);
+        for (surface, location) in surfaces {
+            for item in draw_items(&surface, location) {
+                canvas.draw_item(&item);
+            }
+        }
+        canvas.into_image()
+    }
+}
+
+fn draw_items(surface: &WlSurface, location: Point<i32, Logical>) -> Vec<DrawItem> {
+    let mut items = Vec::new();
+    with_surface_tree_upward(
+        surface,
+        location,
+        |_, surface_data, &location| {
+            TraversalAction::DoChildren(location + subsurface_offset(surface_data))
+        },
+        |_, surface_data, &location| {
+            if let Some(buffer) = current_buffer(surface_data) {
+                let attributes = surface_data.cached_state.current::<SurfaceAttributes>();
+                items.push(DrawItem {
+                    buffer,
+                    location: location + subsurface_offset(surface_data),
+                    buffer_scale: attributes.buffer_scale,
+                    transform: attributes.buffer_transform,
+                });
+            }
+        },
+        |_, _, _| true,
+    );
+    items
+}
+
+/// Area covered by the mapped surfaces of the tree, relative to the root surface.
+pub(crate) fn tree_bounds(surface: &WlSurface) -> Option<Rectangle<i32, Logical>> {
+    let mut bounds: Option<(i32, i32, i32, i32)> = None;
+    for item in draw_items(surface, (0, 0).into()) {
+        if let Some(size) = item.logical_size() {
+            let (left, top) = (item.location.x, item.location.y);
+            let (right, bottom) = (left + size.w, top + size.h);
+            bounds = Some(match bounds {
+                Some((l, t, r, b)) => (l.min(left), t.min(top), r.max(right), b.max(bottom)),
+                None => (left, top, right, bottom),
+            });
+        }
+    }
+    bounds.map(|(left, top, right, bottom)| {
+        Rectangle::from_loc_and_size((left, top), (right - left, bottom - top))
+    })
+}
@@ -0,0 +1,189 @@
+use crate::*;
+use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use futures::stream::{Stream, StreamExt};
+use std::collections::VecDeque;
+use std::os::unix::io::RawFd;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::unix::AsyncFd;
+
+type Command = Box<dyn FnOnce(&mut EmbeddedWaylandServer) + Send>;
+
+/// Runs commands on the server from any task or thread, they are executed the next
+/// time the `WaylandStream` is polled. Protocol objects cannot leave the server thread,
+/// so surfaces are referred to by id.
+#[derive(Clone)]
+pub struct WaylandHandle {
+    sender: UnboundedSender<Command>,
+}
+impl WaylandHandle {
+    pub fn run(&self, command: impl FnOnce(&mut EmbeddedWaylandServer) + Send + 'static) {
+        if self.sender.unbounded_send(Box::new(command)).is_err() {
+            log::warn!(target: "EWS","WaylandHandle: the stream has been dropped, command discarded");
+        }
+    }
+
+    #[cfg(feature = "xdg_shell")]
+    pub fn configure_toplevel(
+        &self,
+        id: SurfaceId,
+        size: Option<Size<i32, Logical>>,
+        states: Vec<SurfaceState>,
+    ) {
+        self.run(move |server| {
+            let result = server.with_window(id, |window| {
+                let mut surface_states = ToplevelStateSet::default();
+                for state in states {
+                    surface_states.set(state);
+                }
+                window.set_pending(size, surface_states);
+                window.schedule_configure();
+            });
+            if let Err(err) = result {
+                log::error!(target: "EWS","configure_toplevel: {}",err);
+            }
+        });
+    }
+
+    pub fn set_keyboard_focus(&self, seat_id: usize, id: Option<SurfaceId>) {
+        self.run(move |server| {
+            let surface = match id {
+                Some(id) => match server.surface(id) {
+                    Some(surface) => Some(surface),
+                    None => {
+                        log::error!(target: "EWS","set_keyboard_focus: {}",ServerError::UnknownSurface(id));
+                        return;
+                    }
+                },
+                None => None,
+            };
+            if let Err(err) = server.set_keyboard_focus(seat_id, surface.as_ref()) {
+                log::error!(target: "EWS","set_keyboard_focus: {}",err);
+            }
+        });
+    }
+
+    pub fn change_output(
+        &self,
+        output_id: usize,
+        mode: Option<OutputMode>,
+        transform: Option<wl_output::Transform>,
+        scale: Option<i32>,
+        location: Option<Point<i32, Logical>>,
+    ) {
+        self.run(move |server| {
+            if let Err(err) = server.change_output(output_id, mode, transform, scale, location) {
+                log::error!(target: "EWS","change_output: {}",err);
+            }
+        });
+    }
+}
+impl std::fmt::Debug for WaylandHandle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WaylandHandle").finish()
+    }
+}
+
+pub struct WaylandStream {
+    async_fd: AsyncFd<RawFd>,
+    server: EmbeddedWaylandServer,
+    commands: UnboundedReceiver<Command>,
+    pending: VecDeque<WaylandRequest>,
+    error: Option<ServerError>,
+}
+impl WaylandStream {
+    /// Must be called from within a tokio runtime.
+    pub fn new(server: EmbeddedWaylandServer) -> Result<(Self, WaylandHandle), ServerError> {
+        let async_fd = AsyncFd::new(server.as_raw_fd()).map_err(ServerError::Dispatch)?;
+        let (sender, commands) = unbounded();
+        let stream = Self {
+            async_fd,
+            server,
+            commands,
+            pending: VecDeque::new(),
+            error: None,
+        };
+        Ok((stream, WaylandHandle { sender }))
+    }
+
+    pub fn server(&self) -> &EmbeddedWaylandServer {
+        &self.server
+    }
+    pub fn server_mut(&mut self) -> &mut EmbeddedWaylandServer {
+        &mut self.server
+    }
+    pub fn take_error(&mut self) -> Option<ServerError> {
+        self.error.take()
+    }
+
+    fn dispatch(&mut self) -> bool {
+        match self.server.dispatch() {
+            Ok(requests) => {
+                self.pending.extend(requests);
+                true
+            }
+            Err(err) => {
+                log::error!(target: "EWS","WaylandStream: {}",err);
+                self.error = Some(err);
+                false
+            }
+        }
+    }
+}
+impl Stream for WaylandStream {
+    type Item = WaylandRequest;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        if this.error.is_some() {
+            return Poll::Ready(None);
+        }
+
+        let mut executed = false;
+        while let Poll::Ready(Some(command)) = this.commands.poll_next_unpin(cx) {
+            command(&mut this.server);
+            executed = true;
+        }
+        if executed && !this.dispatch() {
+            return Poll::Ready(None);
+        }
+
+        loop {
+            if let Some(request) = this.pending.pop_front() {
+                return Poll::Ready(Some(request));
+            }
+            // Requests queued outside of a dispatch, by grabs driven through
+            // `server_mut` for example, do not make the fd readable.
+            if this.server.has_queued_requests() {
+                if !this.dispatch() {
+                    return Poll::Ready(None);
+                }
+                continue;
+            }
+            match this.async_fd.poll_read_ready(cx) {
+                Poll::Ready(Ok(mut guard)) => guard.clear_ready(),
+                Poll::Ready(Err(err)) => {
+                    log::error!(target: "EWS","WaylandStream: {}",err);
+                    this.error = Some(ServerError::Dispatch(err));
+                    return Poll::Ready(None);
+                }
+                Poll::Pending => {
+                    // Events sent by the commands or between dispatches.
+                    this.server.flush_clients();
+                    return Poll::Pending;
+                }
+            }
+            if !this.dispatch() {
+                return Poll::Ready(None);
+            }
+        }
+    }
+}
+impl std::fmt::Debug for WaylandStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WaylandStream")
+            .field("server", &self.server)
+            .field("pending", &self.pending)
+            .finish()
+    }
+}
@@ -0,0 +1,195 @@
+use crate::definitions::*;
+use crate::DispatchContext;
+use slab::Slab;
+use std::cell::{Cell, RefCell};
+use std::rc::{Rc, Weak};
+
+#[derive(Debug, Default)]
+pub(crate) struct SurfaceRegistry {
+    surfaces: Slab<WlSurface>,
+    removed: Vec<SurfaceId>,
+}
+impl SurfaceRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, surface: &WlSurface) -> Option<(SurfaceId, bool)> {
+        let surfaces = &mut self.surfaces;
+        let result = with_states(surface, |surface_data| {
+            if let Some(id) = surface_data.data_map.get::<SurfaceId>() {
+                return (*id, false);
+            }
+            let id = SurfaceId(surfaces.insert(surface.clone()));
+            surface_data.data_map.insert_if_missing(|| id);
+            log::info!(target: "EWS","New surface {:#?}, assigned id {}",surface,id.0);
+            (id, true)
+        });
+        match result {
+            Ok(result) => Some(result),
+            Err(err) => {
+                log::error!(target: "EWS","Error while setting surface id: {:#?}",err);
+                None
+            }
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (SurfaceId, &WlSurface)> {
+        self.surfaces
+            .iter()
+            .filter(move |(key, _)| !self.removed.contains(&SurfaceId(*key)))
+            .map(|(key, surface)| (SurfaceId(key), surface))
+    }
+
+    pub fn get(&self, id: SurfaceId) -> Option<&WlSurface> {
+        if self.removed.contains(&id) {
+            return None;
+        }
+        self.surfaces.get(id.0)
+    }
+
+    /// The id stays reserved until `release_removed` is called,
+    /// once the `SurfaceRemoved` request has been delivered.
+    pub fn mark_removed(&mut self, id: SurfaceId) {
+        if self.surfaces.contains(id.0) && !self.removed.contains(&id) {
+            self.removed.push(id);
+        }
+    }
+
+    pub fn release_removed(&mut self) {
+        for id in self.removed.drain(..) {
+            if self.surfaces.contains(id.0) {
+                self.surfaces.remove(id.0);
+            }
+        }
+    }
+}
+
+pub(crate) fn set_surface_kind(surface: &WlSurface, kind: SurfaceKind) {
+    if let Err(err) = with_states(surface, |surface_data| {
+        surface_data.data_map.insert_if_missing(|| kind);
+    }) {
+        log::error!(target: "EWS","Error while setting surface kind: {:#?}",err);
+    }
+}
+
+#[cfg(feature = "xdg_shell")]
+pub(crate) fn registered_id(surface: &WlSurface) -> Option<SurfaceId> {
+    with_states(surface, |surface_data| {
+        surface_data.data_map.get::<SurfaceId>().copied()
+    })
+    .ok()
+    .flatten()
+}
+
+#[cfg(any(feature = "subcompositor", feature = "xdg_shell"))]
+pub(crate) fn root_surface(surface: &WlSurface) -> WlSurface {
+    let mut root = surface.clone();
+    while let Some(parent) = get_parent(&root) {
+        root = parent;
+    }
+    root
+}
+
+/// Location of the surface relative to the root of its subsurface tree.
+#[cfg(feature = "xdg_shell")]
+pub(crate) fn location_in_root(surface: &WlSurface) -> Point<i32, Logical> {
+    let mut location = Point::from((0, 0));
+    let mut current = surface.clone();
+    while let Some(parent) = get_parent(&current) {
+        location =
+            location + with_states(&current, subsurface_offset).unwrap_or_else(|_| (0, 0).into());
+        current = parent;
+    }
+    location
+}
+
+pub(crate) fn subsurface_offset(surface_data: &SurfaceData) -> Point<i32, Logical> {
+    if surface_data.role == Some("subsurface") {
+        surface_data
+            .cached_state
+            .current::<SubsurfaceCachedState>()
+            .location
+    } else {
+        (0, 0).into()
+    }
+}
+
+struct SurfaceDestructionNotifier(Weak<RefCell<DispatchContext>>);
+struct KnownRole(Cell<Option<&'static str>>);
+
+pub(crate) fn register_surface(
+    dispatch_context: &Rc<RefCell<DispatchContext>>,
+    surface: &WlSurface,
+) -> Option<SurfaceId> {
+    let mut context = dispatch_context.borrow_mut();
+    let (id, created) = context.surfaces.insert(surface)?;
+    if created {
+        let notifier = SurfaceDestructionNotifier(Rc::downgrade(dispatch_context));
+        let _ = with_states(surface, |surface_data| {
+            surface_data.data_map.insert_if_missing(|| notifier);
+        });
+        add_destruction_hook(surface, surface_destroyed);
+        context.requests.push(WaylandRequest::SurfaceCreated {
+            id,
+            surface: surface.clone(),
+        });
+    }
+    if let Some(role) = assigned_role(surface) {
+        context
+            .requests
+            .push(WaylandRequest::SurfaceRoleChanged { id, role });
+    }
+    Some(id)
+}
+
+fn assigned_role(surface: &WlSurface) -> Option<&'static str> {
+    with_states(surface, |surface_data| {
+        surface_data
+            .data_map
+            .insert_if_missing(|| KnownRole(Cell::new(None)));
+        let known_role = &surface_data.data_map.get::<KnownRole>().unwrap().0;
+        if known_role.get() != surface_data.role {
+            known_role.set(surface_data.role);
+            surface_data.role
+        } else {
+            None
+        }
+    })
+    .ok()
+    .flatten()
+}
+
+fn surface_destroyed(surface_data: &SurfaceData) {
+    crate::buffer::release_current(surface_data);
+    let id = match surface_data.data_map.get::<SurfaceId>() {
+        Some(id) => *id,
+        None => return,
+    };
+    let dispatch_context = match surface_data
+        .data_map
+        .get::<SurfaceDestructionNotifier>()
+        .and_then(|notifier| notifier.0.upgrade())
+    {
+        Some(dispatch_context) => dispatch_context,
+        None => return,
+    };
+    match dispatch_context.try_borrow_mut() {
+        Ok(mut context) => {
+            context.surfaces.mark_removed(id);
+            #[cfg(feature = "xdg_shell")]
+            context.windows.remove(&id);
+            context.requests.push(WaylandRequest::SurfaceRemoved { id });
+            // Dismissing popups moves the keyboard focus, which needs the context.
+            #[cfg(feature = "xdg_shell")]
+            {
+                let popups = context.popups.clone();
+                drop(context);
+                popups.surface_destroyed(id);
+            }
+        }
+        Err(_) => {
+            log::error!(target: "EWS","Surface {} destroyed while the dispatch context is in use",id.0)
+        }
+    }
+}
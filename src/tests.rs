@@ -5,8 +5,6 @@ fn create_server() {
     let term_signal = Arc::new(AtomicBool::new(false));
     signal_hook::flag::register(signal_hook::consts::signal::SIGINT, term_signal.clone()).unwrap();
 
-    let mut count = 0;
-
     use crate::*;
 
     let mut parameters = Parameters::default();
@@ -20,11 +18,13 @@ fn create_server() {
             modifier: DrmModifier::Linear,
         },
     ];
+    let validator = SoftwareDmabufValidator::new(parameters.drm_formats.clone());
 
-    let mut server = EmbeddedWaylandServer::new(parameters);
+    let mut server = EmbeddedWaylandServer::new(parameters).unwrap();
+    server.set_dmabuf_validator(validator);
     server.create_seat(0, "Seat-0");
-    server.add_keyboard(0, 200, 25);
-    server.add_cursor(0);
+    server.add_keyboard(0, 200, 25).unwrap();
+    server.add_cursor(0).unwrap();
     server.create_output(
         1,
         "Outpu1",
@@ -36,7 +36,9 @@ fn create_server() {
         },
     );
     while !term_signal.load(Ordering::Relaxed) {
-        let requests = server.dispatch(std::time::Duration::from_secs(1));
+        let requests = server
+            .dispatch_timeout(std::time::Duration::from_secs(1))
+            .unwrap();
         if !requests.is_empty() {
             println!("Outside events {:#?}", requests);
         }
@@ -49,10 +51,11 @@ fn create_server() {
                         XdgRequest::NewToplevel { surface } => {
                             surface.send_configure();
                             //println!("{:#?}",surface.get_surface().unwrap().as_ref().client().unwrap());
+                            println!(
+                                "Toplevel of client {:?}",
+                                server.client_of_surface(surface.get_surface().unwrap())
+                            );
                             with_states(&surface.get_surface().unwrap(), |surface_data| {
-                                let client_id = ClientId(count);
-                                count += 1;
-                                surface_data.data_map.insert_if_missing(|| client_id);
                                 println!(
                                     "Committing {:#?}: {:#?}",
                                     surface,
@@ -68,25 +71,850 @@ fn create_server() {
                         _ => (),
                     }
                 }
-                WaylandRequest::Commit { surface } => {
-                    with_states(&surface, |surface_data| {
-                        let surface_attributes =
-                            surface_data.cached_state.current::<SurfaceAttributes>();
-                        surface_attributes
-                            .buffer
-                            .as_ref()
-                            .map(|buffer| match buffer {
-                                BufferAssignment::NewBuffer { buffer, delta } => {
-                                    let dma_buf = buffer.as_ref().user_data().get::<Dmabuf>();
-                                    println!("Dmabuf: {:#?}", dma_buf);
-                                }
-                                _ => (),
-                            });
-                    })
-                    .unwrap();
+                WaylandRequest::Commit { buffer, .. } => {
+                    if let Some(AttachedBuffer::Dmabuf { dmabuf, .. }) = buffer {
+                        println!("Dmabuf: {:#?}", dmabuf);
+                    }
                 }
                 _ => {}
             }
         })
     }
 }
+
+#[test]
+fn rgba_image_to_ppm() {
+    use crate::*;
+
+    let mut image = RgbaImage::new(2, 1);
+    image
+        .data
+        .copy_from_slice(&[255, 0, 0, 255, 0, 0, 255, 128]);
+    let mut ppm = Vec::new();
+    image.write_ppm(&mut ppm).unwrap();
+    assert_eq!(ppm, b"P6\n2 1\n255\n\xff\x00\x00\x00\x00\xff".to_vec());
+    assert_eq!(image.pixel(1, 0), [0, 0, 255, 128]);
+}
+
+#[cfg(feature = "xdg_shell")]
+#[test]
+fn resize_geometry_from_edges() {
+    use crate::*;
+
+    let initial = Rectangle::from_loc_and_size((100, 100), (400, 300));
+    let none = Size::from((0, 0));
+    assert_eq!(
+        resize_geometry(
+            ResizeEdge::BottomRight,
+            initial,
+            (50, -20).into(),
+            none,
+            none
+        ),
+        Rectangle::from_loc_and_size((100, 100), (450, 280))
+    );
+    assert_eq!(
+        resize_geometry(ResizeEdge::TopLeft, initial, (50, -20).into(), none, none),
+        Rectangle::from_loc_and_size((150, 80), (350, 320))
+    );
+    assert_eq!(
+        resize_geometry(
+            ResizeEdge::Left,
+            initial,
+            (350, 0).into(),
+            (200, 0).into(),
+            none
+        ),
+        Rectangle::from_loc_and_size((300, 100), (200, 300))
+    );
+}
+
+#[cfg(feature = "xdg_shell")]
+fn solve_test_positioner(
+    anchor_rect: (i32, i32),
+    size: (i32, i32),
+    offset: (i32, i32),
+    constraint_adjustment: crate::ConstraintAdjustment,
+) -> crate::Rectangle<i32, crate::Logical> {
+    use crate::*;
+
+    // Parent at (100, 100) in a 1000x800 output, so the constraint area relative
+    // to the parent spans from (-100, -100) to (900, 700).
+    let positioner = PositionerState {
+        rect_size: size.into(),
+        anchor_rect: Rectangle::from_loc_and_size(anchor_rect, (10, 10)),
+        anchor_edges: Anchor::BottomRight,
+        gravity: Gravity::BottomRight,
+        constraint_adjustment,
+        offset: offset.into(),
+        ..Default::default()
+    };
+    solve_positioner(
+        &positioner,
+        Rectangle::from_loc_and_size((100, 100), (400, 300)),
+        Rectangle::from_loc_and_size((0, 0), (1000, 800)),
+    )
+}
+
+#[cfg(feature = "xdg_shell")]
+#[test]
+fn positioner_unconstrained() {
+    use crate::*;
+
+    let rect = |x, y, w, h| Rectangle::from_loc_and_size((x, y), (w, h));
+    let all = ConstraintAdjustment::all();
+    assert_eq!(
+        solve_test_positioner((50, 50), (200, 100), (0, 0), all),
+        rect(60, 60, 200, 100)
+    );
+    assert_eq!(
+        solve_test_positioner((850, 50), (200, 100), (0, 0), ConstraintAdjustment::None),
+        rect(860, 60, 200, 100)
+    );
+}
+
+#[cfg(feature = "xdg_shell")]
+#[test]
+fn positioner_flip() {
+    use crate::*;
+
+    let rect = |x, y, w, h| Rectangle::from_loc_and_size((x, y), (w, h));
+    let flip_x = ConstraintAdjustment::FlipX;
+    let flip_y = ConstraintAdjustment::FlipY;
+    assert_eq!(
+        solve_test_positioner((850, 50), (200, 100), (0, 0), flip_x),
+        rect(650, 60, 200, 100)
+    );
+    // The offset is mirrored along with the anchor and the gravity.
+    assert_eq!(
+        solve_test_positioner((850, 50), (200, 100), (5, 0), flip_x),
+        rect(645, 60, 200, 100)
+    );
+    assert_eq!(
+        solve_test_positioner((50, 650), (200, 100), (0, 0), flip_y),
+        rect(60, 550, 200, 100)
+    );
+    // A flip that does not fit either is reverted.
+    assert_eq!(
+        solve_test_positioner((400, 50), (600, 100), (0, 0), flip_x),
+        rect(410, 60, 600, 100)
+    );
+    assert_eq!(
+        solve_test_positioner((850, 650), (200, 100), (0, 0), flip_x | flip_y),
+        rect(650, 550, 200, 100)
+    );
+}
+
+#[cfg(feature = "xdg_shell")]
+#[test]
+fn positioner_slide() {
+    use crate::*;
+
+    let rect = |x, y, w, h| Rectangle::from_loc_and_size((x, y), (w, h));
+    let slide_x = ConstraintAdjustment::SlideX;
+    let slide_y = ConstraintAdjustment::SlideY;
+    assert_eq!(
+        solve_test_positioner((850, 50), (200, 100), (0, 0), slide_x),
+        rect(700, 60, 200, 100)
+    );
+    assert_eq!(
+        solve_test_positioner((-300, 50), (200, 100), (0, 0), slide_x),
+        rect(-100, 60, 200, 100)
+    );
+    // Too large to fit: the start edge of the constraint wins.
+    assert_eq!(
+        solve_test_positioner((0, 50), (1100, 100), (0, 0), slide_x),
+        rect(-100, 60, 1100, 100)
+    );
+    assert_eq!(
+        solve_test_positioner((50, 650), (200, 100), (0, 0), slide_y),
+        rect(60, 600, 200, 100)
+    );
+    // Flipping comes first, sliding only if the flip did not help.
+    assert_eq!(
+        solve_test_positioner(
+            (400, 50),
+            (600, 100),
+            (0, 0),
+            ConstraintAdjustment::FlipX | slide_x
+        ),
+        rect(300, 60, 600, 100)
+    );
+    assert_eq!(
+        solve_test_positioner(
+            (850, 650),
+            (200, 100),
+            (0, 0),
+            ConstraintAdjustment::FlipX | slide_y
+        ),
+        rect(650, 600, 200, 100)
+    );
+}
+
+#[cfg(feature = "xdg_shell")]
+#[test]
+fn positioner_resize() {
+    use crate::*;
+
+    let rect = |x, y, w, h| Rectangle::from_loc_and_size((x, y), (w, h));
+    let resize_x = ConstraintAdjustment::ResizeX;
+    let resize_y = ConstraintAdjustment::ResizeY;
+    assert_eq!(
+        solve_test_positioner((850, 50), (200, 100), (0, 0), resize_x),
+        rect(860, 60, 40, 100)
+    );
+    assert_eq!(
+        solve_test_positioner((850, 650), (200, 100), (0, 0), resize_x | resize_y),
+        rect(860, 660, 40, 40)
+    );
+    // Resizing only trims what sliding could not fix.
+    assert_eq!(
+        solve_test_positioner(
+            (0, 50),
+            (1100, 100),
+            (0, 0),
+            ConstraintAdjustment::SlideX | resize_x
+        ),
+        rect(-100, 60, 1000, 100)
+    );
+    // A popup entirely outside of the constraint keeps its size.
+    assert_eq!(
+        solve_test_positioner((950, 50), (200, 100), (0, 0), resize_x),
+        rect(960, 60, 200, 100)
+    );
+}
+
+#[cfg(feature = "dma_buf")]
+fn test_dmabuf(size: (i32, i32), code: crate::DrmFourcc, planes: u32) -> crate::Dmabuf {
+    use crate::*;
+    use smithay::backend::allocator::dmabuf::DmabufFlags;
+    use std::os::unix::io::IntoRawFd;
+
+    let mut builder = Dmabuf::builder(size, code, DmabufFlags::empty());
+    for index in 0..planes {
+        let fd = std::fs::File::open("/dev/null").unwrap().into_raw_fd();
+        builder.add_plane(fd, index, 0, size.0 as u32 * 4, DrmModifier::Linear);
+    }
+    builder.build().unwrap()
+}
+
+#[cfg(feature = "dma_buf")]
+#[test]
+fn software_dmabuf_validator() {
+    use crate::*;
+
+    let linear = |code| DrmFormat {
+        code,
+        modifier: DrmModifier::Linear,
+    };
+    let mut validator = SoftwareDmabufValidator::new(vec![
+        linear(DrmFourcc::Argb8888),
+        linear(DrmFourcc::Nv12),
+        linear(DrmFourcc::P010),
+        linear(DrmFourcc::Yuv420),
+    ]);
+    assert!(validator.validate(&test_dmabuf((64, 64), DrmFourcc::Argb8888, 1)));
+    assert!(validator.validate(&test_dmabuf((64, 64), DrmFourcc::Nv12, 2)));
+    assert!(validator.validate(&test_dmabuf((64, 64), DrmFourcc::P010, 2)));
+    assert!(validator.validate(&test_dmabuf((64, 64), DrmFourcc::Yuv420, 3)));
+    // Unsupported format.
+    assert!(!validator.validate(&test_dmabuf((64, 64), DrmFourcc::Xrgb8888, 1)));
+    // Wrong plane count.
+    assert!(!validator.validate(&test_dmabuf((64, 64), DrmFourcc::Argb8888, 2)));
+    assert!(!validator.validate(&test_dmabuf((64, 64), DrmFourcc::Nv12, 1)));
+    // Too large.
+    assert!(!validator.validate(&test_dmabuf((20000, 64), DrmFourcc::Argb8888, 1)));
+}
+
+#[test]
+fn buffer_damage_to_surface() {
+    use crate::damage::buffer_to_surface;
+    use crate::*;
+    use wl_output::Transform;
+
+    let rect = |x, y, w, h| Rectangle::<i32, Logical>::from_loc_and_size((x, y), (w, h));
+    let size: Size<i32, Physical> = (200, 100).into();
+    let damage = (10, 20, 30, 40);
+
+    assert_eq!(
+        buffer_to_surface(damage, size, 1, Transform::Normal),
+        rect(10, 20, 30, 40)
+    );
+    assert_eq!(
+        buffer_to_surface(damage, size, 1, Transform::_90),
+        rect(40, 10, 40, 30)
+    );
+    assert_eq!(
+        buffer_to_surface(damage, size, 1, Transform::_180),
+        rect(160, 40, 30, 40)
+    );
+    assert_eq!(
+        buffer_to_surface(damage, size, 1, Transform::_270),
+        rect(20, 160, 40, 30)
+    );
+    assert_eq!(
+        buffer_to_surface(damage, size, 1, Transform::Flipped),
+        rect(160, 20, 30, 40)
+    );
+    assert_eq!(
+        buffer_to_surface(damage, size, 1, Transform::Flipped90),
+        rect(20, 10, 40, 30)
+    );
+    assert_eq!(
+        buffer_to_surface(damage, size, 1, Transform::Flipped180),
+        rect(10, 40, 30, 40)
+    );
+    assert_eq!(
+        buffer_to_surface(damage, size, 1, Transform::Flipped270),
+        rect(40, 160, 40, 30)
+    );
+    // Scaled damage is rounded outwards to whole logical pixels.
+    assert_eq!(
+        buffer_to_surface((2, 4, 6, 8), size, 2, Transform::Normal),
+        rect(1, 2, 3, 4)
+    );
+    assert_eq!(
+        buffer_to_surface((1, 1, 2, 2), size, 2, Transform::Normal),
+        rect(0, 0, 2, 2)
+    );
+    assert_eq!(
+        buffer_to_surface((10, 20, 30, 40), size, 2, Transform::_180),
+        rect(80, 20, 15, 20)
+    );
+}
+
+#[test]
+fn damage_collapses_to_bounding_box() {
+    use crate::damage::add_damage;
+    use crate::*;
+
+    let rect = |x, y, w, h| Rectangle::<i32, Logical>::from_loc_and_size((x, y), (w, h));
+    let mut damage = Vec::new();
+    for index in 0..32 {
+        add_damage(&mut damage, rect(index * 10, 5, 4, 4));
+    }
+    assert_eq!(damage.len(), 32);
+    add_damage(&mut damage, rect(0, 100, 1, 1));
+    assert_eq!(damage, vec![rect(0, 5, 314, 96)]);
+}
+
+#[cfg(feature = "software_renderer")]
+#[test]
+fn blend_premultiplied_pixels() {
+    use crate::pixel::{blend, premultiply};
+
+    assert_eq!(premultiply([255, 128, 0, 128]), [128, 64, 0, 128]);
+    assert_eq!(premultiply([10, 20, 30, 255]), [10, 20, 30, 255]);
+    assert_eq!(premultiply([10, 20, 30, 0]), [0, 0, 0, 0]);
+
+    let destination = [40, 80, 120, 255];
+    assert_eq!(blend([0, 0, 0, 0], destination), destination);
+    assert_eq!(blend([1, 2, 3, 255], destination), [1, 2, 3, 255]);
+    assert_eq!(
+        blend([0, 0, 128, 128], [255, 255, 255, 255]),
+        [127, 127, 255, 255]
+    );
+}
+
+/// Argb8888 buffer of 2x2 pixels: red, green on the first row, blue, white on the second.
+#[cfg(feature = "software_renderer")]
+fn test_quad_buffer() -> Vec<u8> {
+    vec![
+        0, 0, 255, 255, 0, 255, 0, 255, //
+        255, 0, 0, 255, 255, 255, 255, 255,
+    ]
+}
+
+#[cfg(feature = "software_renderer")]
+fn render_test_quad(
+    canvas_scale: i32,
+    buffer_scale: i32,
+    transform: crate::wl_output::Transform,
+) -> crate::RgbaImage {
+    use crate::software_renderer::{Canvas, PixelSource};
+    use crate::*;
+
+    let data = test_quad_buffer();
+    let source = PixelSource::Shm {
+        data: &data,
+        format: ShmFormat::Argb8888,
+        stride: 8,
+    };
+    let mut canvas = Canvas::new((4, 4).into(), canvas_scale, [0, 0, 0, 255]);
+    canvas.draw(
+        &source,
+        (2, 2).into(),
+        (1, 1).into(),
+        buffer_scale,
+        transform,
+    );
+    canvas.into_image()
+}
+
+#[cfg(feature = "software_renderer")]
+#[test]
+fn software_renderer_golden_pixels() {
+    use crate::wl_output::Transform;
+
+    const BLACK: [u8; 4] = [0, 0, 0, 255];
+    const RED: [u8; 4] = [255, 0, 0, 255];
+    const GREEN: [u8; 4] = [0, 255, 0, 255];
+    const BLUE: [u8; 4] = [0, 0, 255, 255];
+    const WHITE: [u8; 4] = [255, 255, 255, 255];
+
+    let image = render_test_quad(1, 1, Transform::Normal);
+    assert_eq!((image.width, image.height), (4, 4));
+    assert_eq!(image.pixel(0, 0), BLACK);
+    assert_eq!(image.pixel(1, 1), RED);
+    assert_eq!(image.pixel(2, 1), GREEN);
+    assert_eq!(image.pixel(1, 2), BLUE);
+    assert_eq!(image.pixel(2, 2), WHITE);
+    assert_eq!(image.pixel(3, 3), BLACK);
+
+    // The buffer content is turned clockwise to undo its 90 degree transform.
+    let image = render_test_quad(1, 1, Transform::_90);
+    assert_eq!(image.pixel(1, 1), BLUE);
+    assert_eq!(image.pixel(2, 1), RED);
+    assert_eq!(image.pixel(1, 2), WHITE);
+    assert_eq!(image.pixel(2, 2), GREEN);
+
+    let image = render_test_quad(1, 1, Transform::_270);
+    assert_eq!(image.pixel(1, 1), GREEN);
+    assert_eq!(image.pixel(2, 1), WHITE);
+    assert_eq!(image.pixel(1, 2), RED);
+    assert_eq!(image.pixel(2, 2), BLUE);
+
+    let image = render_test_quad(1, 1, Transform::_180);
+    assert_eq!(image.pixel(1, 1), WHITE);
+    assert_eq!(image.pixel(2, 1), BLUE);
+    assert_eq!(image.pixel(1, 2), GREEN);
+    assert_eq!(image.pixel(2, 2), RED);
+
+    let image = render_test_quad(1, 1, Transform::Flipped);
+    assert_eq!(image.pixel(1, 1), GREEN);
+    assert_eq!(image.pixel(2, 1), RED);
+    assert_eq!(image.pixel(1, 2), WHITE);
+    assert_eq!(image.pixel(2, 2), BLUE);
+
+    // An output scale of 2 draws every buffer pixel on 2x2 output pixels.
+    let image = render_test_quad(2, 1, Transform::Normal);
+    assert_eq!((image.width, image.height), (8, 8));
+    assert_eq!(image.pixel(1, 1), BLACK);
+    assert_eq!(image.pixel(2, 2), RED);
+    assert_eq!(image.pixel(3, 3), RED);
+    assert_eq!(image.pixel(4, 2), GREEN);
+    assert_eq!(image.pixel(2, 4), BLUE);
+    assert_eq!(image.pixel(5, 5), WHITE);
+    assert_eq!(image.pixel(6, 6), BLACK);
+
+    // A buffer scale of 2 makes the buffer a single logical pixel, sampled at its center.
+    let image = render_test_quad(1, 2, Transform::Normal);
+    assert_eq!(image.pixel(1, 1), WHITE);
+    assert_eq!(image.pixel(2, 1), BLACK);
+}
+
+#[cfg(feature = "software_renderer")]
+#[test]
+fn software_renderer_blends_translucent_surfaces() {
+    use crate::software_renderer::{Canvas, PixelSource};
+    use crate::*;
+
+    let mut canvas = Canvas::new((2, 1).into(), 1, [255, 255, 255, 255]);
+    canvas.draw(
+        &PixelSource::Solid([0, 0, 128, 128]),
+        (1, 1).into(),
+        (1, 0).into(),
+        1,
+        wl_output::Transform::Normal,
+    );
+    let image = canvas.into_image();
+    assert_eq!(image.pixel(0, 0), [255, 255, 255, 255]);
+    assert_eq!(image.pixel(1, 0), [127, 127, 255, 255]);
+}
+
+#[test]
+fn shm_pixel_formats() {
+    use crate::pixel::{bytes_per_pixel, premultiplied_rgba};
+    use crate::*;
+
+    let pixel = [0x10, 0x20, 0x30, 0x80];
+    assert_eq!(
+        premultiplied_rgba(ShmFormat::Argb8888, &pixel),
+        [0x30, 0x20, 0x10, 0x80]
+    );
+    assert_eq!(
+        premultiplied_rgba(ShmFormat::Xrgb8888, &pixel),
+        [0x30, 0x20, 0x10, 0xff]
+    );
+    assert_eq!(
+        premultiplied_rgba(ShmFormat::Abgr8888, &pixel),
+        [0x10, 0x20, 0x30, 0x80]
+    );
+    assert_eq!(
+        premultiplied_rgba(ShmFormat::Xbgr8888, &pixel),
+        [0x10, 0x20, 0x30, 0xff]
+    );
+
+    let rgb565 = |value: u16| premultiplied_rgba(ShmFormat::Rgb565, &value.to_le_bytes());
+    assert_eq!(rgb565(0xf800), [0xff, 0, 0, 0xff]);
+    assert_eq!(rgb565(0x07e0), [0, 0xff, 0, 0xff]);
+    assert_eq!(rgb565(0x001f), [0, 0, 0xff, 0xff]);
+    assert_eq!(rgb565(0x8410), [132, 130, 132, 0xff]);
+    assert_eq!(rgb565(0xffff), [0xff, 0xff, 0xff, 0xff]);
+
+    assert_eq!(bytes_per_pixel(ShmFormat::Argb8888), Some(4));
+    assert_eq!(bytes_per_pixel(ShmFormat::Rgb565), Some(2));
+    assert_eq!(bytes_per_pixel(ShmFormat::Nv12), None);
+}
+
+#[test]
+fn unpremultiply_round_trip() {
+    use crate::pixel::{premultiplied_rgba, unpremultiply};
+    use crate::*;
+
+    assert_eq!(unpremultiply([0, 0, 0, 0]), [0, 0, 0, 0]);
+    assert_eq!(unpremultiply([10, 20, 30, 255]), [10, 20, 30, 255]);
+    assert_eq!(unpremultiply([64, 32, 0, 128]), [128, 64, 0, 128]);
+
+    for alpha in 1..=255u32 {
+        for value in 0..=255u32 {
+            let premultiplied = ((value * alpha + 127) / 255) as u8;
+            // Argb8888 in little endian: blue, green, red, alpha.
+            let shm_pixel = [premultiplied, premultiplied, premultiplied, alpha as u8];
+            let straight = unpremultiply(premultiplied_rgba(ShmFormat::Argb8888, &shm_pixel));
+            // Converting back gives the same premultiplied pixel.
+            assert_eq!(
+                (straight[0] as u32 * alpha + 127) / 255,
+                premultiplied as u32
+            );
+            // Precision is only lost for highly transparent pixels.
+            if alpha >= 128 {
+                assert!((straight[0] as i32 - value as i32).abs() <= 1);
+            }
+        }
+    }
+}
+
+#[cfg(feature = "xdg_shell")]
+#[test]
+fn configure_serial_order() {
+    use crate::configure::is_after;
+    use crate::*;
+
+    assert!(is_after(Serial::from(5), Serial::from(4)));
+    assert!(!is_after(Serial::from(4), Serial::from(4)));
+    assert!(!is_after(Serial::from(3), Serial::from(4)));
+    // Serials wrap around.
+    assert!(is_after(Serial::from(1), Serial::from(u32::MAX)));
+    assert!(!is_after(Serial::from(u32::MAX), Serial::from(1)));
+}
+
+#[cfg(feature = "xdg_shell")]
+#[test]
+fn popup_grab_serials() {
+    use crate::popup::InputSerials;
+    use crate::*;
+
+    let serials = InputSerials {
+        keyboard: Some(Serial::from(10)),
+        touch_down: Some(Serial::from(20)),
+        popup_button: Some(Serial::from(30)),
+    };
+    assert!(serials.accepts(Serial::from(5), true));
+    assert!(serials.accepts(Serial::from(10), false));
+    assert!(serials.accepts(Serial::from(20), false));
+    assert!(serials.accepts(Serial::from(30), false));
+    assert!(!serials.accepts(Serial::from(5), false));
+    assert!(!InputSerials::default().accepts(Serial::from(10), false));
+}
+
+#[cfg(feature = "xdg_shell")]
+#[test]
+fn popup_stack_grab_and_dismiss() {
+    use crate::popup::PopupStack;
+    use crate::*;
+
+    let mut stack = PopupStack::default();
+    assert!(stack.is_empty());
+    stack.push(Some(SurfaceId(1)), "menu");
+    stack.push(Some(SurfaceId(2)), "submenu");
+    stack.push(None, "tooltip");
+    assert_eq!(stack.top(), Some(&"tooltip"));
+    assert_eq!(
+        stack.iter().copied().collect::<Vec<_>>(),
+        vec!["menu", "submenu", "tooltip"]
+    );
+    // Popups are dismissed from the topmost down.
+    assert_eq!(stack.take_all(), vec!["tooltip", "submenu", "menu"]);
+    assert!(stack.is_empty());
+    assert_eq!(stack.top(), None);
+}
+
+#[cfg(feature = "xdg_shell")]
+#[test]
+fn popup_stack_destroy_chain() {
+    use crate::popup::PopupStack;
+    use crate::*;
+
+    let mut stack = PopupStack::default();
+    for id in 1..=4 {
+        stack.push(Some(SurfaceId(id)), id);
+    }
+    // Unknown surfaces leave the chain alone.
+    assert_eq!(stack.destroyed(SurfaceId(7)), None);
+    // Destroying the topmost popup dismisses nothing else.
+    assert_eq!(stack.destroyed(SurfaceId(4)), Some(vec![]));
+    assert_eq!(stack.top(), Some(&3));
+    // Destroying a popup in the middle dismisses the ones above it, topmost first.
+    stack.push(Some(SurfaceId(5)), 5);
+    assert_eq!(stack.destroyed(SurfaceId(2)), Some(vec![5, 3]));
+    assert_eq!(stack.iter().copied().collect::<Vec<_>>(), vec![1]);
+    assert_eq!(stack.destroyed(SurfaceId(1)), Some(vec![]));
+    assert!(stack.is_empty());
+}
+
+#[cfg(all(feature = "shm", feature = "subcompositor"))]
+static TEST_BUFFER_FILES: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+
+/// In-process client driven in lockstep with the server.
+#[cfg(all(feature = "shm", feature = "subcompositor"))]
+struct TestClient {
+    display: wayland_client::Display,
+    queue: wayland_client::EventQueue,
+    compositor: wayland_client::Main<wayland_client::protocol::wl_compositor::WlCompositor>,
+    subcompositor:
+        wayland_client::Main<wayland_client::protocol::wl_subcompositor::WlSubcompositor>,
+    shm: wayland_client::Main<wayland_client::protocol::wl_shm::WlShm>,
+    files: Vec<std::fs::File>,
+}
+#[cfg(all(feature = "shm", feature = "subcompositor"))]
+impl TestClient {
+    fn connect(server: &mut crate::EmbeddedWaylandServer) -> Self {
+        use std::os::unix::io::IntoRawFd;
+        use wayland_client::protocol::{
+            wl_compositor::WlCompositor, wl_shm::WlShm, wl_subcompositor::WlSubcompositor,
+        };
+
+        let (_id, stream) = server.create_client().unwrap();
+        stream.set_nonblocking(true).unwrap();
+        let display = unsafe { wayland_client::Display::from_fd(stream.into_raw_fd()) }.unwrap();
+        let mut queue = display.create_event_queue();
+        let attached = (*display).clone().attach(queue.token());
+        let globals = wayland_client::GlobalManager::new(&attached);
+        Self::exchange(&display, &mut queue, server);
+        Self {
+            compositor: globals.instantiate_range::<WlCompositor>(1, 4).unwrap(),
+            subcompositor: globals.instantiate_exact::<WlSubcompositor>(1).unwrap(),
+            shm: globals.instantiate_exact::<WlShm>(1).unwrap(),
+            display,
+            queue,
+            files: Vec::new(),
+        }
+    }
+
+    fn exchange(
+        display: &wayland_client::Display,
+        queue: &mut wayland_client::EventQueue,
+        server: &mut crate::EmbeddedWaylandServer,
+    ) -> Vec<crate::WaylandRequest> {
+        let mut requests = Vec::new();
+        for _ in 0..3 {
+            display.flush().unwrap();
+            requests.extend(server.dispatch().unwrap());
+            if let Some(guard) = queue.prepare_read() {
+                if let Err(err) = guard.read_events() {
+                    assert_eq!(err.kind(), std::io::ErrorKind::WouldBlock);
+                }
+            }
+            queue.dispatch_pending(&mut (), |_, _, _| {}).unwrap();
+        }
+        requests
+    }
+
+    /// Sends the queued requests and returns what the server reported.
+    fn roundtrip(
+        &mut self,
+        server: &mut crate::EmbeddedWaylandServer,
+    ) -> Vec<crate::WaylandRequest> {
+        Self::exchange(&self.display, &mut self.queue, server)
+    }
+
+    /// Argb8888 buffer filled with `bgra`, given in memory order.
+    fn buffer(
+        &mut self,
+        width: i32,
+        height: i32,
+        bgra: [u8; 4],
+    ) -> wayland_client::Main<wayland_client::protocol::wl_buffer::WlBuffer> {
+        use std::io::Write;
+        use std::os::unix::io::AsRawFd;
+        use wayland_client::protocol::wl_shm::Format;
+
+        let path = std::env::temp_dir().join(format!(
+            "ews-test-{}-{}",
+            std::process::id(),
+            TEST_BUFFER_FILES.fetch_add(1, Ordering::Relaxed)
+        ));
+        let mut file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let pixels: Vec<u8> = (0..width * height).flat_map(|_| bgra.to_vec()).collect();
+        file.write_all(&pixels).unwrap();
+        let pool = self.shm.create_pool(file.as_raw_fd(), pixels.len() as i32);
+        let buffer = pool.create_buffer(0, width, height, width * 4, Format::Argb8888);
+        pool.destroy();
+        self.files.push(file);
+        buffer
+    }
+}
+
+#[cfg(all(feature = "shm", feature = "subcompositor"))]
+fn created_surfaces(requests: &[crate::WaylandRequest]) -> Vec<crate::SurfaceId> {
+    requests
+        .iter()
+        .filter_map(|request| match request {
+            crate::WaylandRequest::SurfaceCreated { id, .. } => Some(*id),
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(all(feature = "shm", feature = "subcompositor"))]
+#[test]
+fn sync_subsurface_state_applied_on_parent_commit() {
+    use crate::*;
+
+    let mut parameters = Parameters::default();
+    parameters.socket = SocketMode::None;
+    let mut server = EmbeddedWaylandServer::new(parameters).unwrap();
+    let mut client = TestClient::connect(&mut server);
+
+    let parent = client.compositor.create_surface();
+    let child = client.compositor.create_surface();
+    // Subsurfaces are synchronized by default.
+    let _subsurface = client.subcompositor.get_subsurface(&child, &parent);
+    let parent_buffer = client.buffer(4, 4, [0, 0, 255, 255]);
+    parent.attach(Some(&*parent_buffer), 0, 0);
+    parent.commit();
+    let parent_id = created_surfaces(&client.roundtrip(&mut server))[0];
+    server.take_damage(parent_id).unwrap();
+
+    let child_buffer = client.buffer(2, 2, [255, 0, 0, 255]);
+    child.attach(Some(&*child_buffer), 0, 0);
+    child.damage(0, 0, 1, 1);
+    child.commit();
+    let requests = client.roundtrip(&mut server);
+    let child_id = created_surfaces(&requests)[0];
+    // The child state is cached until the parent commits.
+    assert!(!requests
+        .iter()
+        .any(|request| matches!(request, WaylandRequest::Commit { .. })));
+    assert!(server.capture_surface(child_id).unwrap().is_none());
+    assert!(server.take_damage(parent_id).unwrap().is_empty());
+
+    parent.commit();
+    let requests = client.roundtrip(&mut server);
+    let child_surface = server.surface(child_id).unwrap();
+    let child_commit = requests.iter().find_map(|request| match request {
+        WaylandRequest::Commit {
+            surface, buffer, ..
+        } if *surface == child_surface => Some(buffer.as_ref().and_then(AttachedBuffer::size)),
+        _ => None,
+    });
+    assert_eq!(child_commit, Some(Some((2, 2).into())));
+    let image = server.capture_surface(child_id).unwrap().unwrap();
+    assert_eq!(image.pixel(0, 0), [0, 0, 255, 255]);
+    assert_eq!(
+        server.take_damage(parent_id).unwrap(),
+        vec![Rectangle::from_loc_and_size((0, 0), (1, 1))]
+    );
+}
+
+#[cfg(all(
+    feature = "shm",
+    feature = "subcompositor",
+    feature = "software_renderer"
+))]
+#[test]
+fn render_output_golden_pixels() {
+    use crate::*;
+
+    const TRANSPARENT: [u8; 4] = [0, 0, 0, 0];
+    const RED: [u8; 4] = [255, 0, 0, 255];
+    const GREEN: [u8; 4] = [0, 255, 0, 255];
+    const BLUE: [u8; 4] = [0, 0, 255, 255];
+
+    let mut parameters = Parameters::default();
+    parameters.socket = SocketMode::None;
+    let mut server = EmbeddedWaylandServer::new(parameters).unwrap();
+    server.create_output(
+        0,
+        "Output-0",
+        PhysicalProperties {
+            size: (80, 80).into(),
+            subpixel: Subpixel::None,
+            make: String::from(""),
+            model: String::from(""),
+        },
+    );
+    let mut client = TestClient::connect(&mut server);
+
+    // A red window with a blue subsurface at (1, 1).
+    let window = client.compositor.create_surface();
+    let child = client.compositor.create_surface();
+    let subsurface = client.subcompositor.get_subsurface(&child, &window);
+    subsurface.set_position(1, 1);
+    let child_buffer = client.buffer(2, 2, [255, 0, 0, 255]);
+    child.attach(Some(&*child_buffer), 0, 0);
+    child.commit();
+    client.roundtrip(&mut server);
+    let window_buffer = client.buffer(4, 4, [0, 0, 255, 255]);
+    window.attach(Some(&*window_buffer), 0, 0);
+    window.commit();
+    let window_id = created_surfaces(&client.roundtrip(&mut server))[0];
+
+    // A green window stacked above it.
+    let top = client.compositor.create_surface();
+    let top_buffer = client.buffer(2, 2, [0, 255, 0, 255]);
+    top.attach(Some(&*top_buffer), 0, 0);
+    top.commit();
+    let top_id = created_surfaces(&client.roundtrip(&mut server))[0];
+
+    // A window that is not on the output.
+    let hidden = client.compositor.create_surface();
+    let hidden_buffer = client.buffer(2, 2, [255, 255, 255, 255]);
+    hidden.attach(Some(&*hidden_buffer), 0, 0);
+    hidden.commit();
+    let hidden_id = created_surfaces(&client.roundtrip(&mut server))[0];
+
+    server.surface_enter_output(window_id, 0).unwrap();
+    server.surface_enter_output(top_id, 0).unwrap();
+    let image = server
+        .render_output(
+            0,
+            &SoftwareRenderer::default(),
+            (8, 8).into(),
+            vec![
+                (window_id, (1, 1).into()),
+                (top_id, (4, 4).into()),
+                (hidden_id, (6, 0).into()),
+            ],
+        )
+        .unwrap();
+
+    assert_eq!((image.width, image.height), (8, 8));
+    assert_eq!(image.pixel(0, 0), TRANSPARENT);
+    assert_eq!(image.pixel(1, 1), RED);
+    assert_eq!(image.pixel(4, 1), RED);
+    assert_eq!(image.pixel(2, 2), BLUE);
+    assert_eq!(image.pixel(3, 3), BLUE);
+    assert_eq!(image.pixel(4, 4), GREEN);
+    assert_eq!(image.pixel(5, 5), GREEN);
+    assert_eq!(image.pixel(6, 6), TRANSPARENT);
+    assert_eq!(image.pixel(6, 0), TRANSPARENT);
+}
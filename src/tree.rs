@@ -0,0 +1,138 @@
+use crate::buffer::current_buffer;
+use crate::damage::surface_damage;
+use crate::definitions::*;
+use crate::surface::subsurface_offset;
+use std::cell::RefCell;
+
+#[derive(Debug, Clone)]
+pub struct SurfaceNode {
+    pub surface: WlSurface,
+    pub id: Option<SurfaceId>,
+    pub depth: usize,
+    /// Position relative to the parent surface, as committed.
+    pub offset: Point<i32, Logical>,
+    /// Position relative to the root surface.
+    pub location: Point<i32, Logical>,
+    pub synchronized: bool,
+    pub buffer: Option<AttachedBuffer>,
+    pub buffer_scale: i32,
+    pub buffer_transform: wl_output::Transform,
+    /// Accumulated damage, in surface-local logical coordinates.
+    pub damage: Vec<Rectangle<i32, Logical>>,
+    pub opaque_region: Option<RegionAttributes>,
+    pub input_region: Option<RegionAttributes>,
+}
+
+/// Snapshot of the committed state of a root surface and its subsurfaces,
+/// in stacking order from bottom to top.
+#[derive(Debug, Clone)]
+pub struct SurfaceTree {
+    root: WlSurface,
+    nodes: Vec<SurfaceNode>,
+}
+impl SurfaceTree {
+    pub fn new(surface: &WlSurface) -> Self {
+        let root = root_surface(surface);
+        let mut nodes = Vec::new();
+        with_surface_tree_upward(
+            &root,
+            (Point::from((0, 0)), 0),
+            |_surface, surface_data, &(location, depth)| {
+                let offset = subsurface_offset(surface_data);
+                TraversalAction::DoChildren((location + offset, depth + 1))
+            },
+            |surface, surface_data, &(location, depth)| {
+                let offset = subsurface_offset(surface_data);
+                let attributes = surface_data.cached_state.current::<SurfaceAttributes>();
+                nodes.push(SurfaceNode {
+                    surface: surface.clone(),
+                    id: surface_data.data_map.get::<SurfaceId>().copied(),
+                    depth,
+                    offset,
+                    location: location + offset,
+                    synchronized: false,
+                    buffer: current_buffer(surface_data),
+                    buffer_scale: attributes.buffer_scale,
+                    buffer_transform: attributes.buffer_transform,
+                    damage: surface_damage(surface_data),
+                    opaque_region: attributes.opaque_region.clone(),
+                    input_region: attributes.input_region.clone(),
+                });
+            },
+            |_, _, _| true,
+        );
+        // Not done during the traversal, which keeps the surface states locked.
+        for node in &mut nodes {
+            node.synchronized = is_sync_subsurface(&node.surface);
+        }
+        Self { root, nodes }
+    }
+
+    pub fn root(&self) -> &WlSurface {
+        &self.root
+    }
+    pub fn nodes(&self) -> &[SurfaceNode] {
+        &self.nodes
+    }
+    pub fn iter(&self) -> impl Iterator<Item = &SurfaceNode> {
+        self.nodes.iter()
+    }
+    pub fn node(&self, surface: &WlSurface) -> Option<&SurfaceNode> {
+        self.nodes.iter().find(|node| &node.surface == surface)
+    }
+    pub fn children<'a>(&'a self, surface: &'a WlSurface) -> impl Iterator<Item = &'a SurfaceNode> {
+        self.nodes.iter().filter(move |node| {
+            node.surface != *surface && get_parent(&node.surface).as_ref() == Some(surface)
+        })
+    }
+}
+
+pub fn root_surface(surface: &WlSurface) -> WlSurface {
+    crate::surface::root_surface(surface)
+}
+
+// Protocol ids rather than handles, which would keep the root data alive.
+#[derive(Default)]
+struct TreeLayout(RefCell<Vec<(u32, Point<i32, Logical>)>>);
+
+/// Returns the root surface if the hierarchy or the positions of its tree
+/// changed since the last call.
+pub(crate) fn layout_changed(surface: &WlSurface) -> Option<WlSurface> {
+    let root = root_surface(surface);
+    // Runs on every commit, so only positions are collected, no node state.
+    let mut layout = Vec::new();
+    with_surface_tree_upward(
+        &root,
+        Point::from((0, 0)),
+        |_surface, surface_data, &location| {
+            TraversalAction::DoChildren(location + subsurface_offset(surface_data))
+        },
+        |surface, surface_data, &location| {
+            layout.push((
+                surface.as_ref().id(),
+                location + subsurface_offset(surface_data),
+            ));
+        },
+        |_, _, _| true,
+    );
+    with_states(&root, |surface_data| {
+        surface_data.data_map.insert_if_missing(TreeLayout::default);
+        let mut known_layout = surface_data
+            .data_map
+            .get::<TreeLayout>()
+            .unwrap()
+            .0
+            .borrow_mut();
+        // A lone surface is not worth reporting until it gets subsurfaces.
+        let changed = if known_layout.is_empty() {
+            layout.len() > 1
+        } else {
+            *known_layout != layout
+        };
+        *known_layout = layout;
+        changed
+    })
+    .ok()
+    .filter(|changed| *changed)
+    .map(|_| root)
+}
@@ -0,0 +1,138 @@
+use crate::definitions::*;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct WindowState {
+    pub size: Option<Size<i32, Logical>>,
+    pub states: ToplevelStateSet,
+    /// Tracked for the compositor layout only, this Smithay version cannot send
+    /// `xdg_toplevel.configure_bounds`. The acked value is the one pending when the
+    /// acked configure was sent.
+    pub bounds: Option<Size<i32, Logical>>,
+}
+
+/// Follows a toplevel through the configure sequence: the state the compositor wants
+/// (pending), the one the client acknowledged and the one it committed afterwards.
+#[derive(Debug, Clone)]
+pub struct Window {
+    toplevel: ToplevelSurface,
+    pending: WindowState,
+    acked: Option<(Serial, WindowState)>,
+    committed: Option<(Serial, WindowState)>,
+    configure_scheduled: bool,
+    /// Bounds of the configures not acked yet, by the serial taken before sending them.
+    sent_bounds: Vec<(Serial, Option<Size<i32, Logical>>)>,
+}
+impl Window {
+    pub fn new(toplevel: ToplevelSurface) -> Self {
+        Self {
+            toplevel,
+            pending: WindowState::default(),
+            acked: None,
+            committed: None,
+            configure_scheduled: false,
+            sent_bounds: Vec::new(),
+        }
+    }
+
+    pub fn toplevel(&self) -> &ToplevelSurface {
+        &self.toplevel
+    }
+    pub fn pending(&self) -> &WindowState {
+        &self.pending
+    }
+    pub fn acked(&self) -> Option<&WindowState> {
+        self.acked.as_ref().map(|(_serial, state)| state)
+    }
+    pub fn committed(&self) -> Option<&WindowState> {
+        self.committed.as_ref().map(|(_serial, state)| state)
+    }
+    /// Whether the client has not yet committed the pending state.
+    pub fn is_configure_pending(&self) -> bool {
+        self.committed() != Some(&self.pending)
+    }
+
+    pub fn set_size(&mut self, size: Option<Size<i32, Logical>>) {
+        self.pending.size = size;
+    }
+    pub fn set_state(&mut self, state: SurfaceState, enabled: bool) {
+        if enabled {
+            self.pending.states.set(state);
+        } else {
+            self.pending.states.unset(state);
+        }
+    }
+    pub fn set_bounds(&mut self, bounds: Option<Size<i32, Logical>>) {
+        self.pending.bounds = bounds;
+    }
+
+    /// Sends the pending state at the end of the dispatch, unless the configure
+    /// policy configures the window in the same dispatch.
+    pub fn schedule_configure(&mut self) {
+        self.configure_scheduled = true;
+    }
+
+    pub(crate) fn take_scheduled(&mut self) -> bool {
+        std::mem::take(&mut self.configure_scheduled)
+    }
+
+    pub(crate) fn send_pending(&mut self) -> Result<(), DeadResource> {
+        let surface = self.toplevel.get_surface().ok_or(DeadResource)?;
+        let pending = &self.pending;
+        self.toplevel.with_pending_state(|state| {
+            state.size = pending.size;
+            state.states = pending.states.clone();
+        })?;
+        let toplevel = &self.toplevel;
+        let marker = crate::configure::send_tracked(surface, || toplevel.send_configure());
+        self.configure_sent(marker);
+        Ok(())
+    }
+
+    /// Records the bounds pending when a configure was sent.
+    pub(crate) fn configure_sent(&mut self, marker: Serial) {
+        self.sent_bounds.push((marker, self.pending.bounds));
+    }
+
+    /// Replaces the pending size and states.
+    pub(crate) fn set_pending(
+        &mut self,
+        size: Option<Size<i32, Logical>>,
+        states: ToplevelStateSet,
+    ) {
+        self.pending.size = size;
+        self.pending.states = states;
+    }
+
+    pub(crate) fn ack(&mut self, serial: Serial, state: &ToplevelState) {
+        // The acked configure is the last one sent before the ack serial, a configure
+        // sent outside of the crate keeps the previous bounds.
+        let bounds = match self
+            .sent_bounds
+            .iter()
+            .rposition(|(marker, _bounds)| crate::configure::is_after(serial, *marker))
+        {
+            Some(index) => {
+                let bounds = self.sent_bounds[index].1;
+                self.sent_bounds.drain(..=index);
+                bounds
+            }
+            None => self.acked().and_then(|acked| acked.bounds),
+        };
+        let acked = WindowState {
+            size: state.size,
+            states: state.states.clone(),
+            bounds,
+        };
+        self.acked = Some((serial, acked));
+    }
+
+    /// Called on each commit, returns the acked state once it is committed.
+    pub(crate) fn commit(&mut self) -> Option<WindowState> {
+        let (serial, state) = self.acked.as_ref()?;
+        if self.committed.as_ref().map(|(committed, _state)| committed) == Some(serial) {
+            return None;
+        }
+        self.committed = Some((*serial, state.clone()));
+        Some(state.clone())
+    }
+}